@@ -11,6 +11,7 @@ use bsp::hal::{
   pac::{self, interrupt},
   gpio,
   sio,
+  timer,
   watchdog
 };
 use cortex_m as cpu;
@@ -20,26 +21,28 @@ use ehal::digital::v2::{InputPin, OutputPin, PinState};
 use core::convert::Infallible;
 use core::panic::PanicInfo;
 use core::cell::Cell;
-use heapless::Vec;
+use core::fmt::{self, Write};
+use heapless::{String, Vec};
 
 use usb_device::{
   prelude::*,
   class_prelude::*,
 };
-use usbd_hid::{
-  hid_class, descriptor::generator_prelude::*
-};
+use usbd_serial::SerialPort;
 
 use keeb::{
   prelude::*,
   Error,
+  Lighting,
   layout::{Keymap},
   board::{Board},
   bus::{TryIntoInputPin, TryIntoOutputPin},
-  usb::NKROBootKeyboardReport,
+  usb::KeyboardInterface,
   switch_matrix::SwitchMatrix,
   led_matrix::LedMatrix,
+  effects::{Effects, EffectSettings},
   vkeyboard::VKeyboard,
+  clock::{self as kclock, Instant},
 };
 
 #[panic_handler]
@@ -68,6 +71,34 @@ fn set_led(state: bool) {
   });
 }
 
+fn write_fmt_serial(args: fmt::Arguments) {
+  let mut buf = String::<128>::new();
+  buf.write_fmt(args).ok();
+  cpu::interrupt::free(|cs| {
+    let mut usb_interface = Cell::new(None);
+    mutex_usb_interface.borrow(cs).swap(&usb_interface);
+    match usb_interface.get_mut() {
+      Some(UsbInterface{ usb_serial, .. }) => {
+        usb_serial.write(buf.as_bytes()).ok();
+      },
+      _ => {}
+    };
+    mutex_usb_interface.borrow(cs).swap(&usb_interface);
+  });
+}
+
+// serial commands, for diagnostics
+fn run_command(line: &[u8], switches: &mut SwitchMatrix<GpioOut>) {
+  match line {
+    b"chatter" => switches.chatter().report(write_fmt_serial),
+    b"chatter reset" => {
+      switches.reset_chatter();
+      write_fmt_serial(format_args!("ok\r\n"));
+    }
+    _ => write_fmt_serial(format_args!("commands: chatter, chatter reset\r\n")),
+  }
+}
+
 type PinOut = gpio::FunctionSio<gpio::SioOutput>;
 type PinIn = gpio::FunctionSio<gpio::SioInput>;
 type PinPD = gpio::PullDown;
@@ -186,10 +217,22 @@ fn into_user_pins(pins: bsp::Pins) -> UserPins {
 
 type UsbBusAlloc = UsbBusAllocator<hal::usb::UsbBus>;
 type UsbDev<'a> = UsbDevice<'a, hal::usb::UsbBus>;
-type UsbKbdClass<'a> = hid_class::HIDClass<'a, hal::usb::UsbBus>;
+type UsbKbd<'a> = KeyboardInterface<'a, hal::usb::UsbBus>;
+type UsbSerial<'a> = SerialPort<'a, hal::usb::UsbBus>;
 struct UsbInterface<'a> {
   usb_dev: UsbDev<'a>,
-  usb_kbd_class: UsbKbdClass<'a>,
+  usb_kbd: UsbKbd<'a>,
+  usb_serial: UsbSerial<'a>,
+}
+
+// the timer counts microseconds since boot
+#[derive(Clone, Copy)]
+struct TimerClock(timer::Timer);
+
+impl kclock::Clock for TimerClock {
+  fn now(&self) -> Instant {
+    self.0.get_counter().ticks()
+  }
 }
 
 static mutex_usb_interface: Mutex<Cell<Option<UsbInterface>>>
@@ -204,8 +247,8 @@ unsafe fn USBCTRL_IRQ() {
     let mut usb_interface = Cell::new(None);
     mutex_usb_interface.borrow(cs).swap(&usb_interface);
     match usb_interface.get_mut() {
-      Some(UsbInterface{ usb_dev, usb_kbd_class, .. }) => {
-        usb_dev.poll(&mut [usb_kbd_class]);
+      Some(UsbInterface{ usb_dev, usb_kbd, usb_serial }) => {
+        usb_dev.poll(&mut [&mut usb_kbd.boot_class, &mut usb_kbd.nkro_class, usb_serial]);
       },
       _ => {}
     }
@@ -234,6 +277,8 @@ fn rp2040_main() -> ! {
     XTAL_FREQ_HZ, pac.XOSC, pac.CLOCKS, pac.PLL_SYS, pac.PLL_USB,
     &mut pac.RESETS, &mut watchdog).ok().unwrap();
   let mut delay = cpu::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+  let timer = timer::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+  let clock = TimerClock(timer);
   let pins = bsp::Pins::new(
     pac.IO_BANK0,
     pac.PADS_BANK0,
@@ -246,15 +291,8 @@ fn rp2040_main() -> ! {
     pac.USBCTRL_REGS, pac.USBCTRL_DPRAM, clocks.usb_clock, true, &mut pac.RESETS
   )));
   let usb_bus = USB_BUS.as_ref().unwrap();
-  let desc = NKROBootKeyboardReport::desc();
-  let usb_kbd_class = hid_class::HIDClass::new_with_settings(
-    &usb_bus, desc, USB_POLL_MS,
-    hid_class::HidClassSettings {
-      subclass: hid_class::HidSubClass::NoSubClass,
-      protocol: hid_class::HidProtocol::Keyboard,
-      config: hid_class::ProtocolModeConfig::DefaultBehavior,
-      locale: hid_class::HidCountryCode::US,
-    });
+  let usb_kbd = KeyboardInterface::new(&usb_bus, USB_POLL_MS);
+  let usb_serial = SerialPort::new(&usb_bus);
   let usb_dev =
     UsbDeviceBuilder::new(&usb_bus, USB_VID_PID_GEN_KBD)
     .manufacturer("gkanwar")
@@ -264,7 +302,7 @@ fn rp2040_main() -> ! {
   // TODO: OSX doesn't recognize keyboard when HID device class is set
   // .device_class(USB_CLASS_HID)
   let usb_interface = Cell::new(Some(UsbInterface {
-    usb_dev, usb_kbd_class
+    usb_dev, usb_kbd, usb_serial
   }));
   cpu::interrupt::free(|cs| {
     mutex_usb_interface.borrow(cs).swap(&usb_interface);
@@ -302,29 +340,65 @@ fn rp2040_main() -> ! {
   let reg_map = keeb::board::make_reg_map(&board, &layout);
   let mut switches = SwitchMatrix::<GpioOut>::new(
     reg_map.clone(), board_pins.switch_reg_pins).unwrap();
-  let mut leds = LedMatrix::<GpioOut>::new(
-    reg_map,
-    board_pins.backlight_reg_pins,
-    board_pins.backlight_reset_pin,
-    board_pins.backlight_dim_pin);
+  switches.set_debounce(board.debounce);
+  switches.set_chatter(board.chatter);
+  let mut lighting = Lighting {
+    leds: LedMatrix::<GpioOut>::new(
+      reg_map,
+      board_pins.backlight_reg_pins,
+      board_pins.backlight_reset_pin,
+      board_pins.backlight_dim_pin),
+    effects: Effects::new(EffectSettings::default()),
+  };
+  let mut led_ind_pins = board_pins.led_ind_pins;
   let mut vkbd = VKeyboard::new(keymap).unwrap();
 
-  let mut buf: [u8; 64] = [0; 64]; // for usb OUT packets
   let mut pending = false;
+  let mut serial_line = Vec::<u8, 32>::new();
   loop {
     delay.delay_ms(1);
     let (updated, new_in_bus, new_bus_lock) = keeb::tick(
-      in_bus, bus_lock, &mut switches, &mut leds, &mut vkbd, &mut delay
+      in_bus, bus_lock, &mut switches, &mut lighting, &mut vkbd,
+      &mut delay, &clock
     ).unwrap();
     in_bus = new_in_bus;
     bus_lock = new_bus_lock;
     if vkbd.reset {
       hal::rom_data::reset_to_usb_boot(0, 0);
     }
-    if !updated && !pending {
-      continue;
+
+    let mut serial_buf: [u8; 64] = [0; 64];
+    let serial_len = cpu::interrupt::free(|cs| {
+      let mut usb_interface = Cell::new(None);
+      mutex_usb_interface.borrow(cs).swap(&usb_interface);
+      let n = match usb_interface.get_mut() {
+        Some(UsbInterface{ usb_serial, .. }) => usb_serial.read(&mut serial_buf).unwrap_or(0),
+        None => 0,
+      };
+      mutex_usb_interface.borrow(cs).swap(&usb_interface);
+      n
+    });
+    for &byte in serial_buf[..serial_len].iter() {
+      match byte {
+        b'\r' | b'\n' => {
+          if !serial_line.is_empty() {
+            run_command(&serial_line[..], &mut switches);
+            serial_line.clear();
+          }
+        }
+        _ => {
+          serial_line.push(byte).ok();
+        }
+      }
+    }
+
+    // the host LED report still has to be read when there is nothing to send
+    let report = if updated || pending {
+      Some(vkbd.get_report().clone())
     }
-    let mut report = vkbd.get_report().clone();
+    else {
+      None
+    };
     cpu::interrupt::free(|cs| {
       let mut usb_interface = Cell::new(None);
       mutex_usb_interface.borrow(cs).swap(&usb_interface);
@@ -335,38 +409,25 @@ fn rp2040_main() -> ! {
       };
       if configured {
         match usb_interface.get_mut() {
-          Some(UsbInterface{ usb_kbd_class, .. }) => {
-            match usb_kbd_class.pull_raw_output(&mut buf) {
-              Ok(size) => {},
-              Err(UsbError::WouldBlock) => {}, // no data
+          Some(UsbInterface{ usb_kbd, .. }) => {
+            match usb_kbd.pull_leds() {
+              Ok(Some(leds)) => vkbd.set_host_leds(leds),
+              Ok(None) => {}, // no data
               Err(err) => panic!("unexpected read error"),
             }
-            match usb_kbd_class.get_protocol_mode() {
-              Ok(hid_class::HidProtocolMode::Report) => {
-                for i in 0..report.boot_keys.len() {
-                  report.boot_keys[i] = 0;
+            match report {
+              Some(report) => {
+                match usb_kbd.push_report(&report) {
+                  Ok(()) => {
+                    pending = false;
+                  },
+                  Err(UsbError::WouldBlock) => { // buffer full
+                    pending = true;
+                  },
+                  Err(err) => panic!("unexpected write error"),
                 }
               }
-              _ => {}
-            }
-            match usb_kbd_class.push_input(&report) {
-              Ok(size) => {
-                pending = false;
-              },
-              Err(UsbError::WouldBlock) => { // buffer full
-                pending = true;
-              },
-              // usbd-hid bug: error on protocol `Report` with subclass `Boot`
-              // hack: forcibly set protocol mode to Boot :(
-              Err(UsbError::InvalidState) => {
-                pending = true;
-                let protocol = usb_kbd_class.get_protocol_mode();
-                if let Ok(protocol) = protocol {
-                  usb_kbd_class.set_protocol_mode(
-                    protocol, hid_class::ProtocolModeConfig::ForceBoot).unwrap();
-                }
-              },
-              Err(err) => panic!("unexpected write error"),
+              None => {}
             }
           },
           None => {},
@@ -374,6 +435,8 @@ fn rp2040_main() -> ! {
       }
       mutex_usb_interface.borrow(cs).swap(&usb_interface);
     });
+    let caps_lock = vkbd.host_leds().caps_lock();
+    led_ind_pins[0].set_state(PinState::from(caps_lock)).unwrap();
   }
 
   // pause, then reboot into BOOTSEL
@@ -9,6 +9,8 @@ use ehal::digital::v2::{InputPin, OutputPin};
 
 use crate::layout::Layout;
 use crate::bus::TryIntoOutputPin;
+use crate::debounce::DebounceSettings;
+use crate::chatter::ChatterSettings;
 use crate::prelude::*;
 
 type VMatrix<T> = Vec<Vec<T, MAX_COLS>, MAX_ROWS>;
@@ -24,6 +26,11 @@ pub struct Board {
   pub backlight_dim_pin: PinIndex,
   pub backlight_reset_pin: PinIndex,
   pub led_ind_pins: [PinIndex; 2],
+  // software debounce on top of the RC filters
+  #[serde(default)]
+  pub debounce: DebounceSettings,
+  #[serde(default)]
+  pub chatter: ChatterSettings,
 }
 
 pub struct BoardPins<P: InputPin, Q: OutputPin> {
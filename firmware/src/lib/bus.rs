@@ -48,11 +48,12 @@ pub struct OutputBus<Q: OutputPin>
 }
 
 impl<Q: OutputPin<Error=Infallible>> OutputBus<Q> {
+  // same bit order as InputBus::read, first pin is the high bit
   pub fn write(&mut self, state: RegValue) -> () {
     self.pins.iter_mut().enumerate().for_each(
       |i_pin| {
         let (i, pin) = i_pin;
-        pin.set_state(match (state >> i) & 1 {
+        pin.set_state(match (state >> (BUS_WIDTH - 1 - i)) & 1 {
           0 => PinState::Low,
           _ => PinState::High,
         }).unwrap();
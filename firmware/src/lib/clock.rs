@@ -0,0 +1,41 @@
+// Also in firmware_v2/src/lib/clock.rs, the two firmwares are built
+// separately. Keep the copies in step.
+use core::cell::Cell;
+
+// Microseconds since boot. 64 bits never wrap in practice.
+pub type Instant = u64;
+
+pub fn to_ms(time: Instant) -> u32 {
+  (time / 1000) as u32
+}
+
+// A monotonic time source, e.g. the RP2040 timer on device
+pub trait Clock {
+  fn now(&self) -> Instant;
+}
+
+// Clock for host tests, only moves when told to
+#[derive(Debug, Default)]
+pub struct MockClock {
+  now: Cell<Instant>,
+}
+
+impl MockClock {
+  pub fn new(now: Instant) -> Self {
+    Self { now: Cell::new(now) }
+  }
+
+  pub fn advance_us(&self, us: u64) {
+    self.now.set(self.now.get() + us);
+  }
+
+  pub fn advance_ms(&self, ms: u32) {
+    self.advance_us(ms as u64 * 1000);
+  }
+}
+
+impl Clock for MockClock {
+  fn now(&self) -> Instant {
+    self.now.get()
+  }
+}
@@ -0,0 +1,176 @@
+use serde::{Serialize, Deserialize};
+use heapless::Vec;
+
+use crate::prelude::*;
+
+// How a change read from a switch is confirmed before it is reported.
+// The hardware RC filter removes contact bounce, software debounce covers the
+// slower chatter of worn switches.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DebounceAlgorithm {
+  // report every change as read
+  None,
+  // report a change once the key has read the same for the debounce time,
+  // timed per key
+  Defer,
+  // as Defer, but any change on the matrix restarts the time for all keys
+  DeferGlobal,
+  // report a change at once, then ignore the key for the debounce time
+  Eager,
+  // report presses at once, releases once the key has read up for
+  // release_ms, press_ms is unused
+  EagerPressDeferRelease,
+}
+
+// Debounce times apply to the direction of the change, equal times are
+// symmetric debounce.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DebounceSettings {
+  pub algorithm: DebounceAlgorithm,
+  pub press_ms: u16,
+  pub release_ms: u16,
+}
+
+impl Default for DebounceSettings {
+  fn default() -> Self {
+    Self {
+      algorithm: DebounceAlgorithm::None,
+      press_ms: 5,
+      release_ms: 5,
+    }
+  }
+}
+
+// Debounced state of every register, updated as registers are read
+pub struct Debouncer {
+  settings: DebounceSettings,
+  // last values read
+  raw: Vec<RegValue, MAX_REGS>,
+  // values reported
+  state: Vec<RegValue, MAX_REGS>,
+  // per key, time of the last change read, or of the last change reported
+  // for Eager
+  key_ms: Vec<[u32; BUS_WIDTH], MAX_REGS>,
+  // time of the last change read on any register
+  matrix_ms: u32,
+}
+
+impl Debouncer {
+  pub fn new(num_regs: usize, settings: DebounceSettings) -> Self {
+    let mut raw = Vec::new();
+    raw.resize_default(num_regs).unwrap();
+    let mut key_ms = Vec::new();
+    key_ms.resize_default(num_regs).unwrap();
+    Self { settings, state: raw.clone(), raw, key_ms, matrix_ms: 0 }
+  }
+
+  pub fn settings(&self) -> DebounceSettings {
+    self.settings
+  }
+
+  // last value read from a register, before debounce
+  pub fn raw(&self, i_reg: usize) -> RegValue {
+    self.raw[i_reg]
+  }
+
+  // debounced value of a register, given the value just read from it
+  pub fn update(&mut self, i_reg: usize, raw: RegValue, now_ms: u32) -> RegValue {
+    let changed = raw ^ self.raw[i_reg];
+    self.raw[i_reg] = raw;
+    if changed != 0 {
+      self.matrix_ms = now_ms;
+    }
+    let mut state = self.state[i_reg];
+    for i in 0..BUS_WIDTH {
+      let bit = 1 << i;
+      let key_ms = &mut self.key_ms[i_reg][i];
+      let is_changed = changed & bit != 0;
+      let is_down = raw & bit != 0;
+      let was_down = state & bit != 0;
+      // time a change in this direction must wait
+      let wait_ms = if is_down { self.settings.press_ms } else { self.settings.release_ms } as u32;
+      let accept = match self.settings.algorithm {
+        DebounceAlgorithm::None => true,
+        DebounceAlgorithm::Defer => {
+          if is_changed {
+            *key_ms = now_ms;
+          }
+          now_ms.wrapping_sub(*key_ms) >= wait_ms
+        }
+        DebounceAlgorithm::DeferGlobal => now_ms.wrapping_sub(self.matrix_ms) >= wait_ms,
+        DebounceAlgorithm::Eager => {
+          // the key is ignored for the time of the last reported change
+          let hold_ms = if was_down { self.settings.press_ms } else { self.settings.release_ms };
+          now_ms.wrapping_sub(*key_ms) >= hold_ms as u32
+        }
+        DebounceAlgorithm::EagerPressDeferRelease => {
+          if is_changed {
+            *key_ms = now_ms;
+          }
+          is_down || now_ms.wrapping_sub(*key_ms) >= wait_ms
+        }
+      };
+      if accept && is_down != was_down {
+        state ^= bit;
+        if self.settings.algorithm == DebounceAlgorithm::Eager {
+          *key_ms = now_ms;
+        }
+      }
+    }
+    self.state[i_reg] = state;
+    state
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // reported state of bit 0 for each (time, value) read
+  fn run(algorithm: DebounceAlgorithm, reads: &[(u32, u8)]) -> std::vec::Vec<u8> {
+    let settings = DebounceSettings { algorithm, press_ms: 5, release_ms: 10 };
+    let mut debouncer = Debouncer::new(1, settings);
+    reads.iter().map(|&(now_ms, raw)| debouncer.update(0, raw, now_ms) & 1).collect()
+  }
+
+  // a press at 100 ms that chatters for 2 ms, a release at 200 ms that
+  // chatters for 2 ms
+  const READS: [(u32, u8); 12] = [
+    (99, 0), (100, 1), (101, 0), (102, 1), (104, 1), (107, 1),
+    (200, 0), (201, 1), (202, 0), (205, 0), (211, 0), (212, 0),
+  ];
+
+  #[test]
+  fn no_debounce() {
+    assert_eq!(run(DebounceAlgorithm::None, &READS), [0, 1, 0, 1, 1, 1, 0, 1, 0, 0, 0, 0]);
+  }
+
+  #[test]
+  fn defer() {
+    assert_eq!(run(DebounceAlgorithm::Defer, &READS), [0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 0]);
+    assert_eq!(run(DebounceAlgorithm::DeferGlobal, &READS), [0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 0]);
+  }
+
+  #[test]
+  fn eager() {
+    assert_eq!(run(DebounceAlgorithm::Eager, &READS), [0, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(
+      run(DebounceAlgorithm::EagerPressDeferRelease, &READS),
+      [0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+  }
+
+  #[test]
+  fn global_timer_waits_for_all_keys() {
+    let settings = DebounceSettings {
+      algorithm: DebounceAlgorithm::DeferGlobal, press_ms: 5, release_ms: 5,
+    };
+    let mut debouncer = Debouncer::new(2, settings);
+    debouncer.update(0, 0b1, 0);
+    debouncer.update(1, 0b1, 3);
+    // key on register 0 has been stable for 5 ms, but register 1 changed since
+    assert_eq!(debouncer.update(0, 0b1, 5), 0);
+    assert_eq!(debouncer.update(0, 0b1, 8), 0b1);
+    assert_eq!(debouncer.update(1, 0b1, 8), 0b1);
+  }
+}
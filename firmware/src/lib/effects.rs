@@ -0,0 +1,202 @@
+use crate::vkeyboard::{KeyEvent, KeyChange};
+use crate::clock::to_ms;
+use crate::prelude::*;
+
+// brightness per key, indexed by KeyIndex
+pub type Frame = [u8; MAX_KEYS];
+
+#[derive(Debug, Copy, Clone)]
+pub struct EffectSettings {
+  // brightness of every key while the backlight is on, effects only ever
+  // raise a key above it
+  pub on_level: u8,
+  // brightness of a held key
+  pub press_level: u8,
+  // time for a released key to fade out
+  pub fade_ms: u32,
+  // brightness of keys defined on the active layer, indexed by layer, levels
+  // at or below on_level leave the layer unmarked
+  pub layer_levels: [u8; MAX_LAYERS],
+  // time without key events before breathing starts
+  pub idle_ms: u32,
+  pub breathe_period_ms: u32,
+  // peak of the breathing, which starts from on_level
+  pub breathe_level: u8,
+}
+
+impl Default for EffectSettings {
+  fn default() -> Self {
+    Self {
+      on_level: 64,
+      press_level: 255,
+      fade_ms: 500,
+      layer_levels: [0, 128, 192, 255, 255, 255, 255, 255],
+      idle_ms: 30_000,
+      breathe_period_ms: 4_000,
+      breathe_level: 128,
+    }
+  }
+}
+
+fn set_key(mask: &mut KeyMask, idx: usize, value: bool) {
+  if value {
+    mask[idx / 64] |= 1 << (idx % 64);
+  }
+  else {
+    mask[idx / 64] &= !(1 << (idx % 64));
+  }
+}
+
+fn get_key(mask: &KeyMask, idx: usize) -> bool {
+  (mask[idx / 64] >> (idx % 64)) & 1 == 1
+}
+
+// Reactive backlight effects. Key events and the active layer go in, a frame
+// of per-key brightness comes out for the LedMatrix to display.
+pub struct Effects {
+  settings: EffectSettings,
+  held: KeyMask,
+  fading: KeyMask,
+  release_ms: [u32; MAX_KEYS],
+  layer: LayerIndex,
+  layer_keys: KeyMask,
+  last_event_ms: u32,
+  now_ms: u32,
+}
+
+impl Effects {
+  pub fn new(settings: EffectSettings) -> Self {
+    Self {
+      settings,
+      held: [0; KEY_MASK_LEN],
+      fading: [0; KEY_MASK_LEN],
+      release_ms: [0; MAX_KEYS],
+      layer: 0,
+      layer_keys: [0; KEY_MASK_LEN],
+      last_event_ms: 0,
+      now_ms: 0,
+    }
+  }
+
+  pub fn handle_event(&mut self, event: KeyEvent) {
+    let time_ms = to_ms(event.time);
+    match event.change {
+      KeyChange::Down(idx) => {
+        set_key(&mut self.held, idx as usize, true);
+        set_key(&mut self.fading, idx as usize, false);
+      }
+      KeyChange::Up(idx) => {
+        set_key(&mut self.held, idx as usize, false);
+        set_key(&mut self.fading, idx as usize, true);
+        self.release_ms[idx as usize] = time_ms;
+      }
+    }
+    self.last_event_ms = time_ms;
+  }
+
+  pub fn layer(&self) -> LayerIndex {
+    self.layer
+  }
+
+  // highlight `keys` at the brightness configured for `layer`
+  pub fn set_layer(&mut self, layer: LayerIndex, keys: KeyMask) {
+    self.layer = layer;
+    self.layer_keys = keys;
+  }
+
+  // level of keys without an effect of their own
+  fn base(&self) -> u8 {
+    let settings = &self.settings;
+    if self.now_ms.wrapping_sub(self.last_event_ms) < settings.idle_ms {
+      return settings.on_level;
+    }
+    let period = settings.breathe_period_ms.max(2);
+    let t = self.now_ms % period;
+    let half = period / 2;
+    let ramp = if t < half { t } else { period - t };
+    let depth = settings.breathe_level.saturating_sub(settings.on_level);
+    settings.on_level + (depth as u32 * ramp / half) as u8
+  }
+
+  pub fn tick(&mut self, now_ms: u32, frame: &mut Frame) {
+    self.now_ms = now_ms;
+    let settings = self.settings;
+    let base = self.base();
+    let layer_level = settings.layer_levels[self.layer as usize % MAX_LAYERS];
+    for idx in 0..MAX_KEYS {
+      let mut level = base;
+      if get_key(&self.layer_keys, idx) {
+        level = level.max(layer_level);
+      }
+      if get_key(&self.held, idx) {
+        level = level.max(settings.press_level);
+      }
+      else if get_key(&self.fading, idx) {
+        let elapsed = now_ms.wrapping_sub(self.release_ms[idx]);
+        if elapsed < settings.fade_ms {
+          let fade = settings.press_level as u32 * (settings.fade_ms - elapsed)
+            / settings.fade_ms;
+          level = level.max(fade as u8);
+        }
+        else {
+          set_key(&mut self.fading, idx, false);
+        }
+      }
+      frame[idx] = level;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn event(change: KeyChange, time_ms: u32) -> KeyEvent {
+    KeyEvent { change, time: time_ms as u64 * 1000 }
+  }
+
+  #[test]
+  fn press_and_fade() {
+    let mut effects = Effects::new(EffectSettings::default());
+    let mut frame: Frame = [0; MAX_KEYS];
+    effects.tick(0, &mut frame);
+    effects.handle_event(event(KeyChange::Down(3), 0));
+    effects.tick(100, &mut frame);
+    assert_eq!(frame[3], 255);
+    // other keys stay lit at the base level
+    assert_eq!(frame[4], 64);
+    effects.handle_event(event(KeyChange::Up(3), 100));
+    effects.tick(350, &mut frame);
+    assert_eq!(frame[3], 127);
+    effects.tick(600, &mut frame);
+    assert_eq!(frame[3], 64);
+  }
+
+  #[test]
+  fn layer_highlight() {
+    let mut effects = Effects::new(EffectSettings::default());
+    let mut frame: Frame = [0; MAX_KEYS];
+    effects.set_layer(2, [0b101, 0]);
+    effects.tick(0, &mut frame);
+    assert_eq!(&frame[..3], &[192, 64, 192]);
+    effects.set_layer(0, [0b101, 0]);
+    effects.tick(1, &mut frame);
+    assert_eq!(&frame[..3], &[64, 64, 64]);
+  }
+
+  #[test]
+  fn breathe_when_idle() {
+    let settings = EffectSettings::default();
+    let mut effects = Effects::new(settings);
+    let mut frame: Frame = [0; MAX_KEYS];
+    effects.tick(settings.idle_ms - 1, &mut frame);
+    assert_eq!(frame[0], settings.on_level);
+    // middle of a breathing period
+    effects.tick(34_000, &mut frame);
+    assert_eq!(frame[0], settings.breathe_level);
+    assert_eq!(frame[MAX_KEYS - 1], settings.breathe_level);
+    effects.handle_event(event(KeyChange::Down(0), 34_000));
+    effects.tick(38_000, &mut frame);
+    assert_eq!(frame[1], settings.on_level);
+  }
+}
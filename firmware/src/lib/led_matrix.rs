@@ -1,25 +1,127 @@
-use ehal::digital::v2::OutputPin;
+use ehal::digital::v2::{OutputPin, PinState};
+use ehal::blocking::delay::DelayUs;
+use core::convert::Infallible;
 use heapless::Vec;
 
 use crate::board::RegMap;
 use crate::bus::OutputBus;
+use crate::effects::Frame;
 use crate::prelude::*;
 
+// number of software PWM steps per cycle, one step per tick
+pub const BACKLIGHT_LEVELS: u8 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Backlight {
+  pub on: bool,
+  // brightness in 0..=BACKLIGHT_LEVELS
+  pub level: u8,
+}
+
+impl Default for Backlight {
+  fn default() -> Self {
+    Self { on: true, level: BACKLIGHT_LEVELS }
+  }
+}
+
+// The LEDs are latched by one 74LS174 per register, sharing the switch bus for
+// data. Latches are clocked on the rising edge and cleared by the active-low
+// reset pin. The active-high dim pin blanks all LEDs, so brightness is set by
+// PWM on the dim pin. Per-key brightness from a Frame is folded into the same
+// PWM cycle, so no key blinks slower than the cycle.
 pub struct LedMatrix<Q: OutputPin> {
-  reg_map: RegMap, // TODO: is this the right information to hang on to?
+  reg_map: RegMap,
   reg_clk_pins: Vec<Q, MAX_REGS>,
   led_rst_pin: Q,
   led_dim_pin: Q,
+  // desired latch state per register, bit order as in the RegMap
+  reg_state: Vec<RegValue, MAX_REGS>,
+  // registers whose latch does not yet hold reg_state
+  dirty_mask: u32,
+  frame: Frame,
+  backlight: Backlight,
+  pwm_step: u8,
 }
 
-impl<Q: OutputPin> LedMatrix<Q> {
+impl<Q: OutputPin<Error=Infallible>> LedMatrix<Q> {
   pub fn new(
-    reg_map: RegMap, reg_clk_pins: Vec<Q, MAX_REGS>,
-    led_rst_pin: Q, led_dim_pin: Q) -> Self
+    reg_map: RegMap, mut reg_clk_pins: Vec<Q, MAX_REGS>,
+    mut led_rst_pin: Q, mut led_dim_pin: Q) -> Self
   {
-    Self { reg_map, reg_clk_pins, led_rst_pin, led_dim_pin }
+    for pin in reg_clk_pins.iter_mut() {
+      pin.set_low().unwrap();
+    }
+    // clear all latches, released on the first tick
+    led_rst_pin.set_low().unwrap();
+    led_dim_pin.set_high().unwrap();
+    let mut reg_state = Vec::<RegValue, MAX_REGS>::new();
+    reg_state.resize_default(reg_map.regs.len()).unwrap();
+    Self {
+      reg_map, reg_clk_pins, led_rst_pin, led_dim_pin, reg_state,
+      dirty_mask: 0,
+      frame: [u8::MAX; MAX_KEYS],
+      backlight: Backlight::default(),
+      pwm_step: 0,
+    }
+  }
+
+  pub fn backlight(&self) -> Backlight {
+    self.backlight
+  }
+
+  pub fn set_backlight(&mut self, backlight: Backlight) {
+    self.backlight = backlight;
+  }
+
+  pub fn set_frame(&mut self, frame: &Frame) {
+    self.frame = *frame;
+  }
+
+  // Each latch bit is lit for the first steps of the PWM cycle, in
+  // proportion to its key's brightness scaled by the backlight level. Any
+  // brightness above 0 gets at least one step.
+  fn pwm_reg(&self, i_reg: usize) -> RegValue {
+    let mut value: RegValue = 0;
+    for bit in 0..BUS_WIDTH {
+      let key = self.reg_map.regs[i_reg][bit];
+      let level = self.frame[key as usize] as u16 * self.backlight.level as u16;
+      let steps = level.div_ceil(u8::MAX as u16);
+      if (self.pwm_step as u16) < steps {
+        value |= 1 << bit;
+      }
+    }
+    value
   }
-  pub fn tick(&mut self, bus: &mut OutputBus<Q>) {
-    // TODO
+
+  fn set_reg(&mut self, i_reg: usize, value: RegValue) {
+    if self.reg_state[i_reg] != value {
+      self.reg_state[i_reg] = value;
+      self.dirty_mask |= 1 << i_reg;
+    }
+  }
+
+  // Called with the bus turned around to output, between switch reads.
+  pub fn tick<D: DelayUs<u32>>(&mut self, bus: &mut OutputBus<Q>, delay: &mut D) {
+    self.led_rst_pin.set_high().unwrap();
+    for i in 0..self.reg_state.len() {
+      let value = if self.backlight.on { self.pwm_reg(i) } else { 0 };
+      self.set_reg(i, value);
+    }
+    for i in 0..self.reg_state.len() {
+      if (self.dirty_mask >> i) & 1 == 0 {
+        continue;
+      }
+      bus.write(self.reg_state[i]);
+      delay.delay_us(1); // setup time
+      self.reg_clk_pins[i].set_high().unwrap();
+      delay.delay_us(1); // hold time
+      self.reg_clk_pins[i].set_low().unwrap();
+    }
+    self.dirty_mask = 0;
+
+    // one PWM step per tick, lit for the first `level` steps
+    let lit = self.backlight.on && self.pwm_step < self.backlight.level;
+    self.led_dim_pin.set_state(PinState::from(!lit)).unwrap();
+    self.pwm_step = (self.pwm_step + 1) % BACKLIGHT_LEVELS;
   }
 }
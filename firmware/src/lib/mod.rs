@@ -8,9 +8,13 @@ pub mod layout;
 pub mod bus;
 pub mod board;
 pub mod switch_matrix;
+pub mod debounce;
+pub mod chatter;
 pub mod led_matrix;
+pub mod effects;
 pub mod vkeyboard;
 pub mod usb;
+pub mod clock;
 
 pub mod error;
 // re-export all error types
@@ -40,8 +44,12 @@ pub mod prelude {
   pub const USB_CLASS_HID: u8 = 3;
   pub const NKRO_MIN_KEY: u8 = 0x02;
   pub const NKRO_MAX_KEY: u8 = 0x81;
+  // the boot keyboard descriptor's Logical Maximum
+  pub const BOOT_MAX_KEY: u8 = 0x65;
   pub const MIN_MODIFIER: u8 = 0xe0;
   pub const MAX_MODIFIER: u8 = 0xe7;
+  pub const NKRO_LEN: usize = (NKRO_MAX_KEY - NKRO_MIN_KEY + 1) as usize;
+  pub const NUM_MODIFIERS: usize = (MAX_MODIFIER - MIN_MODIFIER + 1) as usize;
   
   // re-export all error types
   pub use crate::error::*;
@@ -50,32 +58,51 @@ use prelude::*;
 
 
 use bus::{TryIntoOutputPin, TryIntoInputPin};
-// TODO: need clock info
-pub fn tick<D: DelayUs<u32>, P: InputPin<Error=Infallible>, Q: OutputPin<Error=Infallible>>(
+
+// The backlight and the effects rendered onto it
+pub struct Lighting<Q: OutputPin> {
+  pub leds: led_matrix::LedMatrix<Q>,
+  pub effects: effects::Effects,
+}
+
+pub fn tick<D: DelayUs<u32>, C: clock::Clock, P: InputPin<Error=Infallible>, Q: OutputPin<Error=Infallible>>(
   bus: bus::InputBus<P>,
   bus_lock: bus::BusLock,
   switches: &mut switch_matrix::SwitchMatrix<Q>,
-  leds: &mut led_matrix::LedMatrix<Q>,
+  lighting: &mut Lighting<Q>,
   vkbd: &mut vkeyboard::VKeyboard,
-  delay: &mut D)
+  delay: &mut D,
+  clock: &C)
   -> Result<(bool, bus::InputBus<P>, bus::BusLock), Error>
 where
   P: TryIntoOutputPin<Pin=Q>,
   Q: TryIntoInputPin<Pin=P>,
 {
+  let Lighting { leds, effects } = lighting;
   let mut out_bus = bus.into_output_bus(bus_lock);
-  leds.tick(&mut out_bus);
+  leds.set_backlight(vkbd.backlight);
+  leds.tick(&mut out_bus, delay);
   let (in_bus, mut bus_lock) = out_bus.into_input_bus();
   let mut updated = false;
   for i in 0..switches.num_regs() {
     let (key_events, new_bus_lock) =
-      switches.subtick(i as RegIndex, &in_bus, bus_lock, delay)?;
+      switches.subtick(i as RegIndex, &in_bus, bus_lock, delay, clock)?;
     bus_lock = new_bus_lock;
+    for &event in key_events.iter() {
+      effects.handle_event(event);
+    }
     let now_updated = vkbd.update(key_events)?;
     updated = updated || now_updated;
     if vkbd.reset {
       break;
     }
   }
+  let layer = vkbd.active_layer();
+  if layer != effects.layer() {
+    effects.set_layer(layer, vkbd.layer_keys(layer));
+  }
+  let mut frame: effects::Frame = [0; MAX_KEYS];
+  effects.tick(clock::to_ms(clock.now()), &mut frame);
+  leds.set_frame(&frame);
   return Ok((updated, in_bus, bus_lock));
 }
@@ -4,7 +4,10 @@ use ehal::blocking::delay::DelayUs;
 use core::convert::Infallible;
 
 use crate::board::RegMap;
-use crate::vkeyboard::KeyEvent;
+use crate::debounce::{Debouncer, DebounceSettings};
+use crate::chatter::{ChatterDetector, ChatterSettings};
+use crate::vkeyboard::{KeyEvent, KeyChange};
+use crate::clock::{Clock, to_ms};
 use crate::prelude::*;
 use crate::bus::{InputBus, BusLock};
 
@@ -12,6 +15,8 @@ pub struct SwitchMatrix<Q: OutputPin> {
   reg_map: RegMap,
   reg_en_pins: Vec<Q, MAX_REGS>,
   reg_state: Vec<RegValue, MAX_REGS>,
+  debouncer: Debouncer,
+  chatter: ChatterDetector,
 }
 
 impl<Q: OutputPin> SwitchMatrix<Q> {
@@ -26,6 +31,8 @@ impl<Q: OutputPin> SwitchMatrix<Q> {
     let mut reg_state = Vec::<RegValue, MAX_REGS>::new();
     reg_state.resize_default(reg_map.regs.len());
     Ok(Self {
+      debouncer: Debouncer::new(reg_map.regs.len(), DebounceSettings::default()),
+      chatter: ChatterDetector::new(ChatterSettings::default()),
       reg_map: reg_map,
       reg_en_pins: reg_en_pins,
       reg_state: reg_state,
@@ -36,9 +43,26 @@ impl<Q: OutputPin> SwitchMatrix<Q> {
     self.reg_map.regs.len()
   }
 
-  pub fn subtick<D: DelayUs<u32>, P: InputPin<Error=Infallible>>(
+  pub fn set_chatter(&mut self, settings: ChatterSettings) {
+    self.chatter = ChatterDetector::new(settings);
+  }
+
+  pub fn chatter(&self) -> &ChatterDetector {
+    &self.chatter
+  }
+
+  pub fn reset_chatter(&mut self) {
+    self.chatter.reset();
+  }
+
+  // before scanning starts, the debounced state starts with all keys up
+  pub fn set_debounce(&mut self, settings: DebounceSettings) {
+    self.debouncer = Debouncer::new(self.num_regs(), settings);
+  }
+
+  pub fn subtick<D: DelayUs<u32>, P: InputPin<Error=Infallible>, C: Clock>(
     &mut self, i_reg: RegIndex, bus: &InputBus<P>,
-    bus_lock: BusLock, delay: &mut D)
+    bus_lock: BusLock, delay: &mut D, clock: &C)
     -> Result<(Vec<KeyEvent, BUS_WIDTH>, BusLock), Error>
   {
     let old_state = self.reg_state[i_reg as usize];
@@ -47,20 +71,32 @@ impl<Q: OutputPin> SwitchMatrix<Q> {
     // guarded by bus lock
     self.reg_en_pins[i_reg as usize].set_low(); // enable
     delay.delay_us(2);
-    let new_state = bus.read();
+    let raw_state = bus.read();
     self.reg_en_pins[i_reg as usize].set_high(); // disable
     delay.delay_us(1);
+    // events are stamped with the time the register was read
+    let time = clock.now();
+    let now_ms = to_ms(time);
 
-    // TODO: software debounce?
+    // chatter is counted on what the switches read, debounced or not
+    let raw_changed = raw_state ^ self.debouncer.raw(i_reg as usize);
+    for i in 0..BUS_WIDTH {
+      if (raw_changed >> i) & 1 == 1 {
+        let key: KeyIndex = self.reg_map.regs[i_reg as usize][i];
+        self.chatter.update(key, (raw_state >> i) & 1 == 1, now_ms);
+      }
+    }
+    let new_state = self.debouncer.update(i_reg as usize, raw_state, now_ms);
     for i in 0..BUS_WIDTH {
       let new_bit = (new_state >> i) & 1;
       let old_bit = (old_state >> i) & 1;
       let key: KeyIndex = self.reg_map.regs[i_reg as usize][i];
       if new_bit != old_bit {
-        match new_bit {
-          0 => events.push(KeyEvent::Up(key)).map_err(|_| Error::VecOverflow)?,
-          _ => events.push(KeyEvent::Down(key)).map_err(|_| Error::VecOverflow)?,
-        }
+        let change = match new_bit {
+          0 => KeyChange::Up(key),
+          _ => KeyChange::Down(key),
+        };
+        events.push(KeyEvent { change, time }).map_err(|_| Error::VecOverflow)?;
       }
     }
 
@@ -2,8 +2,10 @@ use heapless::Vec;
 use core::result::Result;
 
 use crate::prelude::*;
-use crate::usb::{KeyUsageAndIndex, NKROBootKeyboardReport, KeyboardUsage};
+use crate::clock::Instant;
+use crate::usb::{KeyUsageAndIndex, NKROBootKeyboardReport, KeyboardUsage, HostLeds};
 use crate::layout::{Behavior, Keymap};
+use crate::led_matrix::{Backlight, BACKLIGHT_LEVELS};
 
 // Virtual keyboard state follows the QMK model:
 // - One default layer that is always active
@@ -25,6 +27,13 @@ pub struct VKeyboard {
   keymap: Keymap,
   // logical state
   usb_report: NKROBootKeyboardReport,
+  // number of held keys sending each usage, so that a usage is only released
+  // when its last source goes up
+  nkro_counts: [u8; NKRO_LEN],
+  modifier_counts: [u8; NUM_MODIFIERS],
+  // lock state reported by the host
+  host_leds: HostLeds,
+  pub backlight: Backlight,
   pub reset: bool,
 }
 
@@ -164,6 +173,15 @@ fn behavior_to_action(behavior: Behavior) -> Action {
   }
 }
 
+fn get_nkro_key_down(nkro_keys: &[u8], usage: u8) -> bool {
+  if usage < NKRO_MIN_KEY || usage > NKRO_MAX_KEY {
+    return false;
+  }
+  let byte = ((usage - NKRO_MIN_KEY) / 8) as usize;
+  let bit = (usage - NKRO_MIN_KEY) % 8;
+  ((nkro_keys[byte] >> bit) & 1) == 1
+}
+
 impl VKeyboard {
   pub fn new(keymap: Keymap) -> Result<Self, Error> {
     Ok(Self {
@@ -173,6 +191,10 @@ impl VKeyboard {
       key_down_mask: [0; KEY_MASK_LEN],
       keymap: keymap,
       usb_report: NKROBootKeyboardReport::default(),
+      nkro_counts: [0; NKRO_LEN],
+      modifier_counts: [0; NUM_MODIFIERS],
+      host_leds: HostLeds::default(),
+      backlight: Backlight::default(),
       reset: false,
     })
   }
@@ -180,47 +202,85 @@ impl VKeyboard {
   fn apply_kui_down(&mut self, kui: KeyUsageAndIndex) {
     let report = &mut self.usb_report;
     match kui {
-      KeyUsageAndIndex::Normal { usage, byte, bit } => {
-        for i in 0..report.boot_keys.len() {
-          if report.boot_keys[i] == 0 {
-            report.boot_keys[i] = usage;
-            break;
-          }
-        }
+      KeyUsageAndIndex::Normal { byte, bit, .. } => {
         assert!(bit < 8 && byte < report.nkro_keys.len());
+        let count = &mut self.nkro_counts[8*byte + bit];
+        *count = count.saturating_add(1);
         report.nkro_keys[byte] |= (1 << bit) as u8;
       }
       KeyUsageAndIndex::Modifier { bit } => {
+        let count = &mut self.modifier_counts[bit];
+        *count = count.saturating_add(1);
         report.modifier |= (1 << bit) as u8;
       }
     }
+    self.sync_boot_keys();
   }
 
   fn apply_kui_up(&mut self, kui: KeyUsageAndIndex) {
     let report = &mut self.usb_report;
     match kui {
-      KeyUsageAndIndex::Normal { usage, byte, bit } => {
-        for i in (0..report.boot_keys.len()).rev() {
-          if report.boot_keys[i] == usage {
-            report.boot_keys[i] = 0;
-            break;
-          }
-        }
+      KeyUsageAndIndex::Normal { byte, bit, .. } => {
         assert!(bit < 8 && byte < report.nkro_keys.len());
-        report.nkro_keys[byte] &= !((1 << bit) as u8);
+        let count = &mut self.nkro_counts[8*byte + bit];
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+          report.nkro_keys[byte] &= !((1 << bit) as u8);
+        }
       }
       KeyUsageAndIndex::Modifier { bit } => {
-        report.modifier &= !((1 << bit) as u8);
+        let count = &mut self.modifier_counts[bit];
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+          report.modifier &= !((1 << bit) as u8);
+        }
+      }
+    }
+    self.sync_boot_keys();
+  }
+
+  // The 6KRO boot array is derived from the NKRO bitmap, so the two can never
+  // disagree. Keys still held keep their slot and newly held keys are appended
+  // in usage order. With more than 6 keys held, the boot report enters the
+  // ErrorRollOver phantom state (HID 1.11 appendix C) until enough keys are
+  // released. Usages the boot descriptor can't express are left out.
+  fn sync_boot_keys(&mut self) {
+    let report = &mut self.usb_report;
+    let mut boot_keys = [0_u8; 6];
+    let mut n_keys = 0;
+    for &usage in report.boot_keys.iter() {
+      if get_nkro_key_down(&report.nkro_keys, usage) {
+        boot_keys[n_keys] = usage;
+        n_keys += 1;
       }
     }
+    for usage in NKRO_MIN_KEY..=BOOT_MAX_KEY {
+      if !get_nkro_key_down(&report.nkro_keys, usage) || boot_keys[..n_keys].contains(&usage) {
+        continue;
+      }
+      if n_keys == boot_keys.len() {
+        report.boot_keys = [KeyboardUsage::KeyboardErrorRollOver as u8; 6];
+        return;
+      }
+      boot_keys[n_keys] = usage;
+      n_keys += 1;
+    }
+    report.boot_keys = boot_keys;
   }
 
   fn apply_vfunc_down(&mut self, vfunc: VirtualFunction) {
     use VirtualFunction::*;
     match vfunc {
-      VBacklightToggle => {}, // TODO
-      VBacklightUp => {}, // TODO
-      VBacklightDown => {}, // TODO
+      VBacklightToggle => {
+        self.backlight.on = !self.backlight.on;
+      },
+      VBacklightUp => {
+        self.backlight.on = true;
+        self.backlight.level = (self.backlight.level + 1).min(BACKLIGHT_LEVELS);
+      },
+      VBacklightDown => {
+        self.backlight.level = self.backlight.level.saturating_sub(1);
+      },
       VReset => {
         self.reset = true;
       },
@@ -243,9 +303,9 @@ impl VKeyboard {
   fn apply_vfunc_up(&mut self, vfunc: VirtualFunction) {
     use VirtualFunction::*;
     match vfunc {
-      VBacklightToggle => {}, // TODO
-      VBacklightUp => {}, // TODO
-      VBacklightDown => {}, // TODO
+      VBacklightToggle => {},
+      VBacklightUp => {},
+      VBacklightDown => {},
       VReset => {},
       VLayerGoto(i) => {},
       VLayerMod(i) => {
@@ -319,9 +379,9 @@ impl VKeyboard {
   {
     let mut updated = false;
     for event in key_events.into_iter() {
-      let now_updated = match event {
-        KeyEvent::Down(idx) => self.key_down(idx)?,
-        KeyEvent::Up(idx) => self.key_up(idx)?,
+      let now_updated = match event.change {
+        KeyChange::Down(idx) => self.key_down(idx)?,
+        KeyChange::Up(idx) => self.key_up(idx)?,
       };
       updated = updated || now_updated;
       if self.reset {
@@ -334,10 +394,50 @@ impl VKeyboard {
   pub fn get_report<'a>(&'a self) -> &'a NKROBootKeyboardReport {
     &self.usb_report
   }
+
+  pub fn set_host_leds(&mut self, leds: HostLeds) {
+    self.host_leds = leds;
+  }
+
+  pub fn host_leds(&self) -> HostLeds {
+    self.host_leds
+  }
+
+  // highest active layer, i.e. the one that keys resolve to first
+  pub fn active_layer(&self) -> LayerIndex {
+    for i in (0..self.keymap.layers.len()).rev() {
+      if i == self.default_layer as usize || (self.active_layer_mask >> i) & 1 == 1 {
+        return i as LayerIndex;
+      }
+    }
+    self.default_layer
+  }
+
+  // keys with a (non-transparent) behavior on the given layer
+  pub fn layer_keys(&self, layer: LayerIndex) -> KeyMask {
+    let mut mask: KeyMask = [0; KEY_MASK_LEN];
+    let behaviors = match self.keymap.layers.get(layer as usize) {
+      Some(behaviors) => behaviors,
+      None => return mask,
+    };
+    for (idx, behavior) in behaviors.iter().enumerate() {
+      match behavior {
+        Behavior::Transparent | Behavior::Noop => {}
+        _ => mask[idx / 64] |= 1 << (idx % 64),
+      }
+    }
+    mask
+  }
+}
+
+#[derive(Debug,Copy,Clone)]
+pub struct KeyEvent {
+  pub change: KeyChange,
+  pub time: Instant,
 }
 
 #[derive(Debug,Copy,Clone)]
-pub enum KeyEvent {
+pub enum KeyChange {
   Down(KeyIndex),
   Up(KeyIndex),
 }
@@ -10,34 +10,33 @@ use bsp::hal::{
   self,
   adc,
   clocks::{init_clocks_and_plls, Clock, ClocksManager},
+  dma::{self, DMAExt, single_buffer},
   pac::{self, interrupt},
   pio::{self, PIOExt},
   gpio,
+  multicore::{Multicore, Stack},
   sio,
-  timer,
+  timer::{self, Alarm},
   watchdog
 };
 use cortex_m as cpu;
 use cortex_m::interrupt::Mutex;
 use ehal::digital::v2::{InputPin, OutputPin, PinState};
-use ehal::adc::OneShot;
 use ws2812_pio::Ws2812Direct;
 use smart_leds::{SmartLedsWrite, RGB8};
 
 use core::convert::Infallible;
 use core::panic::PanicInfo;
-use core::cell::Cell;
+use core::cell::{Cell, UnsafeCell};
 use core::fmt::{self, Write};
-use heapless::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+use heapless::{Vec, String, spsc};
 
 use usb_device::{
   prelude::*,
   class_prelude::*,
   // descriptor::lang_id::LangID,
 };
-use usbd_hid::{
-  hid_class, descriptor::generator_prelude::*
-};
 use usbd_serial::{
   SerialPort,
   embedded_io::{Write as EWrite, ReadReady},
@@ -48,14 +47,22 @@ use keeb::{
   error::Error,
   layout::Keymap,
   board::Board,
-  bus::AnalogBus,
-  usb::NKROBootKeyboardReport,
+  status::Status,
+  console::{self, LineBuffer},
+  bus::{AnalogBus, BusReading},
+  usb::KeyboardInterface,
   switch_matrix::SwitchMatrix,
-  vkeyboard::VKeyboard,
+  vkeyboard::{VKeyboard, KeyEvent},
+  event_queue::EventQueue,
+  settings::{Flash, SettingsStore, FLASH_SECTOR_SIZE, FLASH_PAGE_SIZE, RECORD_BUF_LEN},
+  profile::{SwitchProfile, MAX_PROFILES},
+  clock::{self as kclock, Clock as _, Instant},
+  schedule::{ScanSchedule, ScanSettings},
 };
 
+#[derive(Clone, Copy, PartialEq)]
 enum NpState {
-  Off, Ok, Panic,
+  Off, Ok, Panic, Status((u8, u8, u8)),
 }
 
 fn get_np_color(state: NpState) -> RGB8 {
@@ -63,11 +70,21 @@ fn get_np_color(state: NpState) -> RGB8 {
     NpState::Off => (0, 0, 0).into(),
     NpState::Ok => (50, 0, 50).into(),
     NpState::Panic => (75, 0, 0).into(),
+    NpState::Status(color) => color.into(),
   }
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+  // the USB and neopixel globals belong to core 0, which reports the panic
+  if sio::Sio::core() == sio::CoreId::Core1 {
+    let message = unsafe { &mut *core::ptr::addr_of_mut!(CORE1_PANIC) };
+    write!(message, "{}", info).ok();
+    CORE1_PANICKED.store(true, Ordering::Release);
+    loop {
+      cpu::asm::wfe();
+    }
+  }
   // set panic led
   set_neopixel(NpState::Panic);
   // write panic info to serial repeatedly
@@ -311,36 +328,393 @@ fn into_user_pins<'timer, C: Clock>(
   }
 }
 
+type AdcPins = (adc::AdcPin<PinA0>, adc::AdcPin<PinA1>, adc::AdcPin<PinA2>, adc::AdcPin<PinA3>);
+// set in FIFO entries when the conversion failed
+const ADC_FIFO_ERR: u16 = 1 << 15;
+
+// All four bus channels are captured per mux select in one round-robin burst
+// of the ADC, moved out of its FIFO by DMA. The FIFO stays paused between
+// bursts, so every burst is taken after the mux has settled.
 struct AdcBus {
-  pins: (adc::AdcPin<PinA0>, adc::AdcPin<PinA1>, adc::AdcPin<PinA2>, adc::AdcPin<PinA3>),
-  adc: adc::Adc,
+  pins: AdcPins,
+  // ADC channel of each bus bit
+  channels: [u8; BUS_WIDTH],
+  fifo: adc::AdcFifo<'static, u16>,
+  // held by the transfer during a burst
+  dma: Option<dma::Channel<dma::CH0>>,
+  buf: Option<&'static mut [u16; BUS_WIDTH]>,
+}
+
+impl AdcBus {
+  fn new(
+    pins: AdcPins, adc: &'static mut adc::Adc, dma: dma::Channel<dma::CH0>,
+    buf: &'static mut [u16; BUS_WIDTH]) -> Self
+  {
+    let channels = [pins.0.channel(), pins.1.channel(), pins.2.channel(), pins.3.channel()];
+    let fifo = adc.build_fifo()
+      .round_robin((&pins.0, &pins.1, &pins.2, &pins.3))
+      .enable_dma()
+      .start_paused();
+    Self { pins, channels, fifo, dma: Some(dma), buf: Some(buf) }
+  }
 }
 
 impl AnalogBus for AdcBus {
-  fn read(&mut self) -> RegValue {
-    [
-      self.adc.read(&mut self.pins.0).unwrap_or(0),
-      self.adc.read(&mut self.pins.1).unwrap_or(0),
-      self.adc.read(&mut self.pins.2).unwrap_or(0),
-      self.adc.read(&mut self.pins.3).unwrap_or(0),
-    ]
+  fn read(&mut self) -> BusReading {
+    // round robin samples in channel order, starting from the selected one
+    let first = *self.channels.iter().min().unwrap();
+    unsafe {
+      (*pac::ADC::ptr()).cs().modify(|_, w| w.ainsel().bits(first));
+    }
+    let transfer = single_buffer::Config::new(
+      self.dma.take().unwrap(), self.fifo.dma_read_target(), self.buf.take().unwrap()
+    ).start();
+    self.fifo.resume();
+    let (ch, _, buf) = transfer.wait();
+    self.fifo.pause();
+    // a conversion in progress still lands in the FIFO
+    while !self.fifo.is_ready() {}
+    self.fifo.clear();
+
+    let mut values: BusReading = [None; BUS_WIDTH];
+    for (value, &channel) in values.iter_mut().zip(self.channels.iter()) {
+      let raw = buf[(channel - first) as usize];
+      // dropped rather than read as 0, which would be a press for S polarity
+      *value = if raw & ADC_FIFO_ERR != 0 { None } else { Some(raw) };
+    }
+    self.dma = Some(ch);
+    self.buf = Some(buf);
+    values
+  }
+}
+
+// settings store in the last 64K of flash, reserved in memory.x
+const FLASH_SIZE: usize = 2048 * 1024;
+const STORE_SIZE: usize = 64 * 1024;
+const STORE_OFFSET: usize = FLASH_SIZE - STORE_SIZE;
+const XIP_BASE: usize = 0x1000_0000;
+// 4K sector erase
+const FLASH_SECTOR_ERASE_CMD: u8 = 0x20;
+// serialized settings, only touched by the store on core 0
+static mut SETTINGS_BUF: [u8; RECORD_BUF_LEN] = [0; RECORD_BUF_LEN];
+
+// ROM flash routines, looked up before XIP goes away
+struct FlashFns {
+  connect_internal_flash: unsafe extern "C" fn(),
+  flash_exit_xip: unsafe extern "C" fn(),
+  flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+  flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+  flash_flush_cache: unsafe extern "C" fn(),
+  // copy of boot2 in RAM, restores fast XIP afterwards
+  boot2: unsafe extern "C" fn(),
+}
+
+// boot2 is 256 bytes, copied out of flash once at startup
+static mut BOOT2_COPY: [u32; 64] = [0; 64];
+
+// Runs from RAM, since flash cannot be read while it is being written. The
+// caller must keep interrupts off, as handlers run from flash. `data` is null
+// for an erase.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn flash_op_in_ram(fns: &FlashFns, addr: u32, data: *const u8, len: usize) {
+  (fns.connect_internal_flash)();
+  (fns.flash_exit_xip)();
+  if data.is_null() {
+    (fns.flash_range_erase)(addr, len, FLASH_SECTOR_SIZE as u32, FLASH_SECTOR_ERASE_CMD);
+  }
+  else {
+    (fns.flash_range_program)(addr, data, len);
+  }
+  (fns.flash_flush_cache)();
+  (fns.boot2)();
+}
+
+struct RomFlash {
+  fns: FlashFns,
+}
+
+impl RomFlash {
+  fn new() -> Self {
+    let fns = unsafe {
+      let boot2 = &mut *core::ptr::addr_of_mut!(BOOT2_COPY);
+      core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), boot2.len());
+      FlashFns {
+        connect_internal_flash: hal::rom_data::connect_internal_flash::ptr(),
+        flash_exit_xip: hal::rom_data::flash_exit_xip::ptr(),
+        flash_range_erase: hal::rom_data::flash_range_erase::ptr(),
+        flash_range_program: hal::rom_data::flash_range_program::ptr(),
+        flash_flush_cache: hal::rom_data::flash_flush_cache::ptr(),
+        // thumb bit set
+        boot2: core::mem::transmute((boot2.as_ptr() as *const u8).add(1)),
+      }
+    };
+    Self { fns }
+  }
+
+  // One sector erase or page program at a time, with interrupts and core 1
+  // back in between, so a whole record does not stall USB or scanning.
+  fn run(&mut self, offset: usize, data: *const u8, len: usize) {
+    // core 1 runs from flash too, so it waits in RAM meanwhile
+    let parked = park_core1();
+    cpu::interrupt::free(|_| unsafe {
+      flash_op_in_ram(&self.fns, (STORE_OFFSET + offset) as u32, data, len);
+    });
+    if parked {
+      resume_core1();
+    }
+  }
+}
+
+impl Flash for RomFlash {
+  fn capacity(&self) -> usize {
+    STORE_SIZE
+  }
+
+  fn read(&self, offset: usize, buf: &mut [u8]) {
+    let src = (XIP_BASE + STORE_OFFSET + offset) as *const u8;
+    unsafe {
+      core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+    }
+  }
+
+  fn erase(&mut self, offset: usize) {
+    self.run(offset, core::ptr::null(), FLASH_SECTOR_SIZE);
+  }
+
+  fn program(&mut self, offset: usize, data: &[u8]) {
+    for (i, page) in data.chunks(FLASH_PAGE_SIZE).enumerate() {
+      self.run(offset + i * FLASH_PAGE_SIZE, page.as_ptr(), page.len());
+    }
   }
 }
 
 type UsbBusAlloc = UsbBusAllocator<hal::usb::UsbBus>;
 type UsbDev<'a> = UsbDevice<'a, hal::usb::UsbBus>;
-type UsbKbdClass<'a> = hid_class::HIDClass<'a, hal::usb::UsbBus>;
+type UsbKbd<'a> = KeyboardInterface<'a, hal::usb::UsbBus>;
 type UsbSerialClass<'a> = SerialPort<'a, hal::usb::UsbBus>;
 struct UsbInterface<'a> {
   usb_dev: UsbDev<'a>,
-  usb_kbd_class: UsbKbdClass<'a>,
+  usb_kbd: UsbKbd<'a>,
   usb_serial_class: UsbSerialClass<'a>,
 }
 
+// the timer counts microseconds since boot
+#[derive(Clone, Copy)]
+struct TimerClock(timer::Timer);
+
+impl kclock::Clock for TimerClock {
+  fn now(&self) -> Instant {
+    self.0.get_counter().ticks()
+  }
+}
+
+// The switch matrix is scanned on core 1 and configured from core 0. The
+// `Mutex<Cell<..>>` globals only mask interrupts on the current core, so it
+// is guarded by a hardware spinlock instead.
+type SwitchesLock = sio::Spinlock1;
+struct SharedSwitches {
+  switches: UnsafeCell<Option<SwitchMatrix<GpioOut>>>,
+}
+
+unsafe impl Sync for SharedSwitches {}
+
+impl SharedSwitches {
+  const fn new() -> Self {
+    Self { switches: UnsafeCell::new(None) }
+  }
+
+  // before core 1 starts
+  fn init(&self, switches: SwitchMatrix<GpioOut>) {
+    unsafe {
+      *self.switches.get() = Some(switches);
+    }
+  }
+
+  fn lock<R>(&self, f: impl FnOnce(&mut SwitchMatrix<GpioOut>) -> R) -> R {
+    let _lock = loop {
+      if let Some(lock) = SwitchesLock::try_claim() {
+        break lock;
+      }
+      // core 1 may have panicked holding the lock
+      check_core1();
+    };
+    f(unsafe { self.get() })
+  }
+
+  fn try_lock<R>(&self, f: impl FnOnce(&mut SwitchMatrix<GpioOut>) -> R) -> Option<R> {
+    let _lock = SwitchesLock::try_claim()?;
+    Some(f(unsafe { self.get() }))
+  }
+
+  // only while holding the lock
+  unsafe fn get(&self) -> &mut SwitchMatrix<GpioOut> {
+    (*self.switches.get()).as_mut().unwrap()
+  }
+}
+
+static SWITCHES: SharedSwitches = SharedSwitches::new();
+// read by core 1 for per-layer actuation, set by core 0
+static ACTIVE_LAYER: AtomicU8 = AtomicU8::new(0);
+// matrix state for the status display, published by core 1 after each scan
+// so that core 0 only takes the lock for commands
+static SCAN_CALIBRATING: AtomicBool = AtomicBool::new(false);
+static SCAN_FAULT: AtomicBool = AtomicBool::new(false);
+// key events core 1 could not hand to core 0
+static SCAN_EVENTS_DROPPED: AtomicU32 = AtomicU32::new(0);
+static CORE1_RUNNING: AtomicBool = AtomicBool::new(false);
+static CORE1_PANICKED: AtomicBool = AtomicBool::new(false);
+static mut CORE1_PANIC: String<256> = String::new();
+static mut CORE1_STACK: Stack<4096> = Stack::new();
+// key events in flight from core 1 to core 0, one slot is always free
+const KEY_EVENTS_LEN: usize = 64;
+
+// SIO FIFO words from core 0 to core 1 and back
+const CORE1_PARK: u32 = 1;
+const CORE1_PARKED: u32 = 2;
+const CORE1_RESUME: u32 = 3;
+// SIO FIFO registers, used directly while flash is unavailable
+const SIO_FIFO_ST: *mut u32 = 0xd000_0050 as *mut u32;
+const SIO_FIFO_WR: *mut u32 = 0xd000_0054 as *mut u32;
+const SIO_FIFO_RD: *mut u32 = 0xd000_0058 as *mut u32;
+const SIO_FIFO_ST_VLD: u32 = 1 << 0;
+const SIO_FIFO_ST_RDY: u32 = 1 << 1;
+
+fn check_core1() {
+  if CORE1_PANICKED.load(Ordering::Acquire) {
+    let message = unsafe { &*core::ptr::addr_of!(CORE1_PANIC) };
+    panic!("core 1: {}", message);
+  }
+}
+
+// Stops core 1 between scans, returns whether it was running
+fn park_core1() -> bool {
+  if !CORE1_RUNNING.load(Ordering::Acquire) {
+    return false;
+  }
+  let mut fifo = sio::Sio::new(unsafe { pac::Peripherals::steal() }.SIO).fifo;
+  fifo.write_blocking(CORE1_PARK);
+  while fifo.read_blocking() != CORE1_PARKED {}
+  true
+}
+
+fn resume_core1() {
+  let mut fifo = sio::Sio::new(unsafe { pac::Peripherals::steal() }.SIO).fifo;
+  fifo.write_blocking(CORE1_RESUME);
+}
+
+// Runs from RAM on core 1 until core 0 has finished with flash. Core 0 may
+// turn flash off as soon as it reads the handshake word, so nothing after it
+// may call into flash. That includes interrupts, since the vector table and
+// the handlers are in flash, so the caller must disable them on this core
+// first and enable them only after this returns. Without the cortex-m `inline-asm` feature
+// `cpu::asm::sev` is such a call, hence the inline `sev` to wake core 0 from
+// `read_blocking`.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn core1_park_in_ram() {
+  while core::ptr::read_volatile(SIO_FIFO_ST) & SIO_FIFO_ST_RDY == 0 {}
+  core::ptr::write_volatile(SIO_FIFO_WR, CORE1_PARKED);
+  core::arch::asm!("sev", options(nomem, nostack, preserves_flags));
+  loop {
+    if core::ptr::read_volatile(SIO_FIFO_ST) & SIO_FIFO_ST_VLD != 0
+      && core::ptr::read_volatile(SIO_FIFO_RD) == CORE1_RESUME {
+      return;
+    }
+  }
+}
+
+// Sleeps until the alarm for the next scan, or any other interrupt.
+// Interrupts are masked between the check and `wfi`, so an alarm that fires
+// in between still ends the wait.
+fn wait_for_scan(clock: &TimerClock, deadline: Instant) {
+  cpu::interrupt::free(|cs| {
+    let mut alarm = Cell::new(None);
+    mutex_scan_alarm.borrow(cs).swap(&alarm);
+    if let Some(alarm) = alarm.get_mut() {
+      alarm.schedule_at(timer::Instant::from_ticks(deadline)).ok();
+    }
+    mutex_scan_alarm.borrow(cs).swap(&alarm);
+  });
+  while clock.now() < deadline {
+    cpu::interrupt::free(|_| {
+      if clock.now() < deadline {
+        cpu::asm::wfi();
+      }
+    });
+  }
+}
+
+// Scans the switches on core 1 at a fixed rate, so that USB, serial and the
+// keymap on core 0 cannot delay it. Key events go to core 0 through a
+// lock-free queue.
+fn scan_core1(
+  mut bus: AdcBus, clock: TimerClock, scan: ScanSettings,
+  mut key_events: spsc::Producer<'static, KeyEvent, KEY_EVENTS_LEN>) -> !
+{
+  let mut fifo = sio::Sio::new(unsafe { pac::Peripherals::steal() }.SIO).fifo;
+  // the SysTick delay stays with core 0
+  let mut delay = clock.0;
+  let mut timer = clock.0;
+  let mut alarm = timer.alarm_0().unwrap();
+  alarm.enable_interrupt();
+  let alarm = Cell::new(Some(alarm));
+  cpu::interrupt::free(|cs| {
+    mutex_scan_alarm.borrow(cs).swap(&alarm);
+  });
+  // handled on this core only
+  unsafe {
+    pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+  }
+  let mut schedule = ScanSchedule::new(scan, clock.now()).unwrap();
+  let mut events = EventQueue::new();
+  let mut dropped = 0;
+  loop {
+    wait_for_scan(&clock, schedule.deadline());
+    // core 0 holds the lock while configuring, possibly while writing flash
+    let mut scanned = false;
+    while !scanned {
+      if fifo.read() == Some(CORE1_PARK) {
+        // a scan alarm while flash is off would fault, it is taken on resume
+        cpu::interrupt::disable();
+        unsafe {
+          core1_park_in_ram();
+          cpu::interrupt::enable();
+        }
+      }
+      scanned = SWITCHES.try_lock(|switches| {
+        switches.set_layer(ACTIVE_LAYER.load(Ordering::Relaxed));
+        let scan_start = clock.now();
+        let (late_us, missed) = schedule.start(scan_start);
+        switches.scan_time.record_start(late_us, missed);
+        for i in 0..switches.num_regs() {
+          switches.subtick(i as RegIndex, &mut bus, &mut delay, &clock, &mut events, |_| {})
+            .unwrap();
+          while let Some(event) = events.pop() {
+            if key_events.enqueue(event).is_err() {
+              dropped += 1;
+              SCAN_EVENTS_DROPPED.store(dropped, Ordering::Relaxed);
+            }
+          }
+        }
+        // timer ticks are microseconds
+        switches.scan_time.record((clock.now() - scan_start) as u32);
+        SCAN_CALIBRATING.store(switches.is_calibrating(), Ordering::Relaxed);
+        SCAN_FAULT.store(switches.has_faults(), Ordering::Relaxed);
+      }).is_some();
+    }
+    // wake core 0 to process the events
+    cpu::asm::sev();
+  }
+}
+
 static mutex_usb_interface: Mutex<Cell<Option<UsbInterface>>>
   = Mutex::new(Cell::new(None));
 static mutex_neopixel: Mutex<Cell<Option<Neopixel>>>
   = Mutex::new(Cell::new(None));
+// cleared by the interrupt that wakes core 1 for a scan
+static mutex_scan_alarm: Mutex<Cell<Option<timer::Alarm0>>>
+  = Mutex::new(Cell::new(None));
 // delay timer must be global for panic handler
 static mutex_delay: Mutex<Cell<Option<cpu::delay::Delay>>>
   = Mutex::new(Cell::new(None));
@@ -352,8 +726,8 @@ unsafe fn USBCTRL_IRQ() {
     let mut usb_interface = Cell::new(None);
     mutex_usb_interface.borrow(cs).swap(&usb_interface);
     match usb_interface.get_mut() {
-      Some(UsbInterface{ usb_dev, usb_kbd_class, usb_serial_class, .. }) => {
-        usb_dev.poll(&mut [usb_kbd_class, usb_serial_class]);
+      Some(UsbInterface{ usb_dev, usb_kbd, usb_serial_class, .. }) => {
+        usb_dev.poll(&mut [&mut usb_kbd.boot_class, &mut usb_kbd.nkro_class, usb_serial_class]);
       },
       _ => {}
     }
@@ -361,6 +735,20 @@ unsafe fn USBCTRL_IRQ() {
   });
 }
 
+// Runs on core 1, which is the only core to unmask it
+#[allow(non_snake_case)]
+#[interrupt]
+unsafe fn TIMER_IRQ_0() {
+  cpu::interrupt::free(|cs| {
+    let mut alarm = Cell::new(None);
+    mutex_scan_alarm.borrow(cs).swap(&alarm);
+    if let Some(alarm) = alarm.get_mut() {
+      alarm.clear_interrupt();
+    }
+    mutex_scan_alarm.borrow(cs).swap(&alarm);
+  });
+}
+
 // generic keyboard
 // https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
 const USB_VID_PID_GEN_KBD: UsbVidPid = UsbVidPid(0x16c0, 0x27db);
@@ -371,12 +759,16 @@ const USB_POLL_MS: u8 = 1;
 fn rp2040_main() -> ! {
   // usb bus must be static lifetime for interrupts
   static mut USB_BUS: Option<UsbBusAlloc> = None;
+  // the ADC FIFO borrows the ADC for good, and DMA needs a static buffer
+  static mut ADC: Option<adc::Adc> = None;
+  static mut ADC_BUF: [u16; BUS_WIDTH] = [0; BUS_WIDTH];
+  static mut KEY_EVENTS: spsc::Queue<KeyEvent, KEY_EVENTS_LEN> = spsc::Queue::new();
 
   // init board state and components
   let mut pac = pac::Peripherals::take().unwrap();
   let core = pac::CorePeripherals::take().unwrap();
   let mut watchdog = watchdog::Watchdog::new(pac.WATCHDOG);
-  let sio = sio::Sio::new(pac.SIO);
+  let mut sio = sio::Sio::new(pac.SIO);
   const XTAL_FREQ_HZ: u32 = 12_000_000_u32;
   let clocks = init_clocks_and_plls(
     XTAL_FREQ_HZ, pac.XOSC, pac.CLOCKS, pac.PLL_SYS, pac.PLL_USB,
@@ -384,7 +776,8 @@ fn rp2040_main() -> ! {
   let delay = cpu::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
   let mut delay_cell = Cell::new(Some(delay));
   return_delay(delay_cell);
-  let adc = adc::Adc::new(pac.ADC, &mut pac.RESETS);
+  let adc = ADC.insert(adc::Adc::new(pac.ADC, &mut pac.RESETS));
+  let dma = pac.DMA.split(&mut pac.RESETS);
   let pins = hal::gpio::bank0::Pins::new(
     pac.IO_BANK0,
     pac.PADS_BANK0,
@@ -393,6 +786,7 @@ fn rp2040_main() -> ! {
   );
   let (mut pio0, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
   let timer = timer::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+  let clock = TimerClock(timer);
 
   // split up pins, init neopixel for diagnostics
   let mut user_pins = into_user_pins(pins, &mut pio0, sm0, &clocks.peripheral_clock);
@@ -407,15 +801,7 @@ fn rp2040_main() -> ! {
     pac.USBCTRL_REGS, pac.USBCTRL_DPRAM, clocks.usb_clock, true, &mut pac.RESETS
   )));
   let usb_bus = USB_BUS.as_ref().unwrap();
-  let desc = NKROBootKeyboardReport::desc();
-  let usb_kbd_class = hid_class::HIDClass::new_with_settings(
-    &usb_bus, desc, USB_POLL_MS,
-    hid_class::HidClassSettings {
-      subclass: hid_class::HidSubClass::NoSubClass,
-      protocol: hid_class::HidProtocol::Keyboard,
-      config: hid_class::ProtocolModeConfig::DefaultBehavior,
-      locale: hid_class::HidCountryCode::US,
-    });
+  let usb_kbd = KeyboardInterface::new(&usb_bus, USB_POLL_MS);
   let usb_serial = SerialPort::new(&usb_bus);
   let str_desc = StringDescriptors::new(LangID::EN)
     .manufacturer("gkanwar")
@@ -428,7 +814,7 @@ fn rp2040_main() -> ! {
     .device_class(0x00) // composite
     .build();
   let usb_interface = Cell::new(Some(UsbInterface {
-    usb_dev, usb_kbd_class, usb_serial_class: usb_serial
+    usb_dev, usb_kbd, usb_serial_class: usb_serial
   }));
   cpu::interrupt::free(|cs| {
     mutex_usb_interface.borrow(cs).swap(&usb_interface);
@@ -452,6 +838,18 @@ fn rp2040_main() -> ! {
     serde_json::from_slice(include_bytes!("../../boards/unchat-40.json"))
     .unwrap();
   write_serial(b"Loaded board config.\r\n");
+  // load switch profiles
+  let (profiles, _bytes_read): (Vec<SwitchProfile, MAX_PROFILES>, usize) =
+    serde_json::from_slice(include_bytes!("../../profiles/switches.json"))
+    .unwrap();
+  let profile = board.switch_profile.as_ref().and_then(|name| {
+    let profile = profiles.iter().find(|p| p.name == *name && p.is_valid()).cloned();
+    if profile.is_none() {
+      write_fmt_serial(format_args!("Unknown switch profile {}.\r\n", name));
+    }
+    profile
+  });
+  let status_config = keymap.status.clone().unwrap_or_else(|| board.status.clone());
 
   let board_pins =
     keeb::board::split_pins(user_pins.general_pins, user_pins.general_ids, &board).unwrap();
@@ -461,44 +859,98 @@ fn rp2040_main() -> ! {
     || user_pins.analog_pins.3.id().num != board.bus_pins[3] {
       panic!("Board bus pins mismatch");
   }
-  let mut bus = AdcBus {
-    pins: (
+  let mut bus = AdcBus::new(
+    (
       adc::AdcPin::new(user_pins.analog_pins.0).unwrap_or_else(|_| panic!()),
       adc::AdcPin::new(user_pins.analog_pins.1).unwrap_or_else(|_| panic!()),
       adc::AdcPin::new(user_pins.analog_pins.2).unwrap_or_else(|_| panic!()),
       adc::AdcPin::new(user_pins.analog_pins.3).unwrap_or_else(|_| panic!()),
     ),
-    adc,
-  };
+    adc, dma.ch0, ADC_BUF,
+  );
 
   let reg_map = keeb::board::make_reg_map(&board, &layout);
   let mut switches = SwitchMatrix::<GpioOut>::new(
     reg_map.clone(), board_pins.sel_pins).unwrap();
+  switches.auto_calibration = board.auto_calibration;
+  switches.set_filter(board.filter).unwrap();
+  switches.set_drift(board.drift);
+  switches.set_faults(board.faults);
+  switches.set_scan(board.scan);
+  switches.set_debounce(board.debounce);
+  switches.set_chatter(board.chatter);
+  switches.set_profile(profile);
+  for ov in keymap.actuation.iter() {
+    switches.set_override(*ov).unwrap();
+  }
   let mut vkbd = VKeyboard::new(keymap).unwrap();
+  let mut events = EventQueue::new();
+
+  // stored settings replace the compiled-in defaults
+  let settings_buf = unsafe { &mut *core::ptr::addr_of_mut!(SETTINGS_BUF) };
+  let mut store = SettingsStore::new(RomFlash::new(), settings_buf);
+  match store.load() {
+    Some(settings) => match settings.apply(&mut switches, &mut vkbd) {
+      Ok(()) => write_serial(b"Loaded stored settings.\r\n"),
+      Err(err) => write_fmt_serial(format_args!(
+        "Stored settings rejected: {:?}\r\n", err)),
+    },
+    None => write_serial(b"No stored settings, using defaults.\r\n"),
+  }
+  // disable keys with broken sensors before they send anything
+  let mut delay_cell = grab_delay();
+  match delay_cell.get_mut() {
+    Some(delay) => switches.self_test(&mut bus, delay, write_fmt_serial).unwrap(),
+    None => panic!("missing delay"),
+  };
+  return_delay(delay_cell);
+  // indicators: Caps Lock, Caps Word
+  let mut led_ind_pins = board_pins.led_ind_pins;
   write_serial(b"Established switch matrix and virtual keyboard.\r\n");
+
+  // hand scanning to core 1
+  SCAN_FAULT.store(switches.has_faults(), Ordering::Relaxed);
+  SWITCHES.init(switches);
+  let (key_events_in, mut key_events) = KEY_EVENTS.split();
+  let mut mc = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
+  let cores = mc.cores();
+  let scan = board.scan;
+  // only ever handed to core 1, once
+  let core1_stack = unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK.mem) };
+  cores[1].spawn(core1_stack, move || {
+    scan_core1(bus, clock, scan, key_events_in)
+  }).unwrap();
+  CORE1_RUNNING.store(true, Ordering::Release);
   write_serial(b"Running main loop.\r\n");
 
-  let mut buf: [u8; 64] = [0; 64]; // for usb OUT packets
   let mut pending = false;
+  let mut np_state = NpState::Ok;
+  let mut console_lines = LineBuffer::new();
+  let mut was_calibrating = false;
+  let mut scan_dropped = 0;
   loop {
-    let mut delay_cell = grab_delay();
-    let delay = match delay_cell.get_mut() {
-      Some(delay) => delay,
-      None => panic!("missing delay"),
-    };
-    delay.delay_ms(1);
-    // let mut log_buf = WriteBuf::<4196>::new();
-    let (updated, new_bus) = keeb::tick(
-      bus, &mut switches, &mut vkbd, delay, |args: fmt::Arguments<'_>| {
-        // write_fmt_serial(args);
+    check_core1();
+    // the event queue is smaller than the one from core 1, so it may take
+    // several updates to catch up
+    let mut updated = false;
+    while let Some(event) = key_events.dequeue() {
+      events.push(event).ok();
+      if events.len() == MAX_EVENTS {
+        updated = vkbd.update(&mut events, |_| {}).unwrap() || updated;
       }
-    ).unwrap();
-    // write_serial(&log_buf.data[..]);
-    bus = new_bus;
-    return_delay(delay_cell);
-
-    if vkbd.reset {
-      hal::rom_data::reset_to_usb_boot(0, 0);
+    }
+    updated = vkbd.update(&mut events, |_| {}).unwrap() || updated;
+    // timeouts fire even when no key changed
+    updated = vkbd.poll(clock.now()) || updated;
+    ACTIVE_LAYER.store(vkbd.active_layer(), Ordering::Relaxed);
+    if vkbd.calibrate {
+      vkbd.calibrate = false;
+      SWITCHES.lock(|switches| switches.start_calibration(write_fmt_serial));
+    }
+    let dropped = SCAN_EVENTS_DROPPED.load(Ordering::Relaxed);
+    if dropped != scan_dropped {
+      write_fmt_serial(format_args!("{} key events dropped by core 1\r\n", dropped - scan_dropped));
+      scan_dropped = dropped;
     }
 
     let report = if updated || pending {
@@ -509,8 +961,9 @@ fn rp2040_main() -> ! {
       None
     };
 
-    
-    cpu::interrupt::free(|cs| {
+    let mut serial_buf: [u8; 128] = [0; 128];
+    let mut serial_len = 0;
+    let usb_configured = cpu::interrupt::free(|cs| {
       let mut usb_interface = Cell::new(None);
       mutex_usb_interface.borrow(cs).swap(&usb_interface);
       let configured = match usb_interface.get_mut() {
@@ -520,39 +973,21 @@ fn rp2040_main() -> ! {
       };
       if configured {
         match usb_interface.get_mut() {
-          Some(UsbInterface{ usb_kbd_class, usb_serial_class, .. }) => {
-            match usb_kbd_class.pull_raw_output(&mut buf) {
-              Ok(size) => {},
-              Err(UsbError::WouldBlock) => {}, // no data
+          Some(UsbInterface{ usb_kbd, usb_serial_class, .. }) => {
+            match usb_kbd.pull_leds() {
+              Ok(Some(leds)) => vkbd.set_host_leds(leds),
+              Ok(None) => {}, // no data
               Err(err) => panic!("unexpected read error"),
             }
             match report {
-              Some(mut report) => {
-                match usb_kbd_class.get_protocol_mode() {
-                  Ok(hid_class::HidProtocolMode::Report) => {
-                    for i in 0..report.boot_keys.len() {
-                      report.boot_keys[i] = 0;
-                    }
-                  }
-                  _ => {}
-                }
-                match usb_kbd_class.push_input(&report) {
-                  Ok(size) => {
+              Some(report) => {
+                match usb_kbd.push_report(&report) {
+                  Ok(()) => {
                     pending = false;
                   },
                   Err(UsbError::WouldBlock) => { // buffer full
                     pending = true;
                   },
-                  // usbd-hid bug: error on protocol `Report` with subclass `Boot`
-                  // hack: forcibly set protocol mode to Boot :(
-                  Err(UsbError::InvalidState) => {
-                    pending = true;
-                    let protocol = usb_kbd_class.get_protocol_mode();
-                    if let Ok(protocol) = protocol {
-                      usb_kbd_class.set_protocol_mode(
-                        protocol, hid_class::ProtocolModeConfig::ForceBoot).unwrap();
-                    }
-                  },
                   Err(err) => panic!("unexpected write error"),
                 }
               }
@@ -560,12 +995,12 @@ fn rp2040_main() -> ! {
             }
             // get serial input
             if usb_serial_class.read_ready().unwrap_or(false) {
-              let mut buf: [u8; 128] = [0; 128];
-              match usb_serial_class.read(&mut buf[..]) {
+              match usb_serial_class.read(&mut serial_buf[..]) {
                 Ok(n) => {
-                  // FORNOW: echo messages
-                  usb_serial_class.write(&buf[..n]).ok();
+                  // echo for interactive terminals
+                  usb_serial_class.write(&serial_buf[..n]).ok();
                   usb_serial_class.flush().ok();
+                  serial_len = n;
                 }
                 Err(UsbError::WouldBlock) => {}
                 Err(_) => {
@@ -578,7 +1013,55 @@ fn rp2040_main() -> ! {
         }
       }
       mutex_usb_interface.borrow(cs).swap(&usb_interface);
+      configured
     });
+
+    for &byte in serial_buf[..serial_len].iter() {
+      if let Some(line) = console_lines.push(byte) {
+        write_serial(b"\r\n");
+        match console::parse_command(&line[..]) {
+          Ok(cmd) => SWITCHES.lock(|switches| console::run_command(
+            cmd, switches, &mut events, &mut vkbd, &mut store, &clock, write_fmt_serial)),
+          Err(err) => write_fmt_serial(format_args!("error: {}\r\n", err)),
+        }
+      }
+    }
+
+    let calibrating = SCAN_CALIBRATING.load(Ordering::Relaxed);
+    let fault = SCAN_FAULT.load(Ordering::Relaxed);
+    // keep the results of a finished calibration
+    if was_calibrating && !calibrating {
+      SWITCHES.lock(|switches| {
+        console::save_settings(switches, &vkbd, &mut store, write_fmt_serial)
+      });
+    }
+    was_calibrating = calibrating;
+
+    let caps_lock = vkbd.host_leds().caps_lock();
+    led_ind_pins[0].set_state(PinState::from(caps_lock)).unwrap();
+    led_ind_pins[1].set_state(PinState::from(vkbd.caps_word())).unwrap();
+    let status = Status {
+      layer: vkbd.active_layer(),
+      caps_lock,
+      caps_word: vkbd.caps_word(),
+      one_shot: vkbd.one_shot_pending(),
+      calibrating,
+      fault,
+      bootloader: vkbd.reset,
+      usb_configured,
+    };
+    let now_ms = kclock::to_ms(clock.now());
+    let new_np_state = NpState::Status(status_config.color(&status, now_ms));
+    if new_np_state != np_state {
+      set_neopixel(new_np_state);
+      np_state = new_np_state;
+    }
+
+    if vkbd.reset {
+      hal::rom_data::reset_to_usb_boot(0, 0);
+    }
+    // sleep until core 1 finishes a scan or an interrupt needs handling
+    cpu::asm::wfe();
   }
 
   // // pause, then reboot into BOOTSEL
@@ -8,19 +8,217 @@ use core::str;
 use ehal::digital::v2::{InputPin, OutputPin};
 
 use crate::layout::Layout;
+use crate::status::StatusConfig;
+use crate::calibration::CalibrationSettings;
+use crate::filter::FilterSettings;
+use crate::health::FaultSettings;
+use crate::chatter::ChatterSettings;
+use crate::schedule::ScanSettings;
+use crate::profile::MAX_PROFILE_NAME;
 use crate::prelude::*;
 
 type VMatrix<T> = Vec<Vec<T, MAX_COLS>, MAX_ROWS>;
 type Matrix<T> = [[T; MAX_COLS]; MAX_ROWS];
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Polarity {N, S}
 
+// Normalized values as whole ADC counts, rounded so that integer readings
+// compare against them exactly as against the normalized value: value < x is
+// value < ceil(x), and value > x is value > floor(x). The scan loop only
+// compares integers, the M0+ has no FPU.
+pub fn floor_counts(norm: f32) -> i32 {
+  let x = norm * ADC_MAX as f32;
+  let i = x as i32;
+  if i as f32 > x { i - 1 } else { i }
+}
+
+pub fn ceil_counts(norm: f32) -> i32 {
+  let x = norm * ADC_MAX as f32;
+  let i = x as i32;
+  if (i as f32) < x { i + 1 } else { i }
+}
+
+// Rapid trigger distances, in the same normalized units as the thresholds
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct RapidTrigger<T = f32> {
+  // rise from the deepest point that releases the key
+  pub release: T,
+  // fall from the highest point that re-actuates the key
+  pub press: T,
+}
+
+// Second actuation point near bottom-out, with its own hysteresis
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct DeepTrigger<T = f32> {
+  pub trig_down: T,
+  pub trig_up: T,
+}
+
+// Tracking of each switch's resting value, which drifts with temperature and
+// supply voltage. Thresholds move along with it.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DriftSettings {
+  // time constant of the baseline average in seconds, 0.0 disables tracking
+  pub time_constant_s: f32,
+  // samples further than this from the baseline are not tracked, since the
+  // key is moving
+  pub band: f32,
+  // limit on how far the baseline may move from its initial value
+  pub max_drift: f32,
+}
+
+impl DriftSettings {
+  pub fn to_fixed(&self, scan: &ScanSettings) -> FixedDriftSettings {
+    // weight of a new sample, one scan's share of the time constant
+    let rate = if self.time_constant_s > 0.0 {
+      1.0 / (self.time_constant_s * scan.rate_hz as f32)
+    }
+    else {
+      0.0
+    };
+    FixedDriftSettings {
+      // the cast saturates, so 1.0 ends up just short of 2^32
+      rate: (rate.clamp(0.0, 1.0) * u32::MAX as f32) as u32,
+      band: floor_counts(self.band),
+      max_drift: floor_counts(self.max_drift),
+    }
+  }
+}
+
+// DriftSettings in ADC counts, with the rate as a fraction of 2^32
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FixedDriftSettings {
+  pub rate: u32,
+  pub band: i32,
+  pub max_drift: i32,
+}
+
+impl Default for DriftSettings {
+  fn default() -> Self {
+    Self {
+      time_constant_s: 10.0,
+      band: 0.02,
+      max_drift: 0.05,
+    }
+  }
+}
+
+// Consecutive samples past a threshold before a key changes state, 1 acts on
+// the first sample. Hysteresis alone still lets a single noisy sample actuate
+// a key, a press of 2 or more filters such spikes at the cost of latency of
+// one scan each. A release of 1 with a longer press is eager to release and
+// careful to press, the reverse keeps quick presses at the risk of late
+// releases.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Debounce {
+  pub press_samples: u8,
+  pub release_samples: u8,
+}
+
+impl Default for Debounce {
+  fn default() -> Self {
+    Self {
+      press_samples: 1,
+      release_samples: 1,
+    }
+  }
+}
+
+// Normalized readings at rest and at bottom-out, as measured by
+// auto-calibration
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwitchRange {
+  pub rest: f32,
+  pub bottom: f32,
+}
+
+// Actuation points in mm of travel from rest, converted with the board's
+// switch profile
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TravelTrigger {
+  pub down_mm: f32,
+  pub up_mm: f32,
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct SwitchSettings {
   pub polarity: Polarity,
   pub trig_down: f32,
   pub trig_up: f32,
+  // while the key is past trig_up, actuate and release on changes in
+  // direction instead of at fixed thresholds
+  #[serde(default)]
+  pub rapid_trigger: Option<RapidTrigger>,
+  // sends the key's deep_layers behavior instead when pressed past it
+  #[serde(default)]
+  pub deep_trigger: Option<DeepTrigger>,
+  #[serde(default)]
+  pub range: Option<SwitchRange>,
+  // replaces trig_down and trig_up once the switch has a range
+  #[serde(default)]
+  pub travel: Option<TravelTrigger>,
+  // replaces the board's debounce for this key
+  #[serde(default)]
+  pub debounce: Option<Debounce>,
+}
+
+impl SwitchSettings {
+  // thresholds must leave room for hysteresis in the polarity's direction
+  pub fn is_valid(&self) -> bool {
+    match self.polarity {
+      Polarity::S => self.trig_up > self.trig_down,
+      Polarity::N => self.trig_up < self.trig_down,
+    }
+  }
+
+  // Thresholds in ADC counts, for comparison against raw readings. S keys
+  // actuate below trig_down and release above trig_up, N keys the reverse.
+  pub fn to_fixed(&self) -> FixedSwitchSettings {
+    let (trig_down, trig_up) = match self.polarity {
+      Polarity::S => (ceil_counts(self.trig_down), floor_counts(self.trig_up)),
+      Polarity::N => (floor_counts(self.trig_down), ceil_counts(self.trig_up)),
+    };
+    let debounce = self.debounce.unwrap_or_default();
+    FixedSwitchSettings {
+      polarity: self.polarity,
+      trig_down,
+      trig_up,
+      // 0 would never change state
+      press_samples: debounce.press_samples.max(1),
+      release_samples: debounce.release_samples.max(1),
+      // compared as distances, exceeded when greater
+      rapid_trigger: self.rapid_trigger.map(|rt| RapidTrigger {
+        release: floor_counts(rt.release),
+        press: floor_counts(rt.press),
+      }),
+      // S is deep below trig_down and stays deep up to trig_up inclusive
+      deep_trigger: self.deep_trigger.map(|deep| match self.polarity {
+        Polarity::S => DeepTrigger {
+          trig_down: ceil_counts(deep.trig_down),
+          trig_up: floor_counts(deep.trig_up),
+        },
+        Polarity::N => DeepTrigger {
+          trig_down: floor_counts(deep.trig_down),
+          trig_up: ceil_counts(deep.trig_up),
+        },
+      }),
+    }
+  }
+}
+
+// SwitchSettings converted for the scan loop, see to_fixed
+#[derive(Debug, Copy, Clone)]
+pub struct FixedSwitchSettings {
+  pub polarity: Polarity,
+  pub trig_down: i32,
+  pub trig_up: i32,
+  pub rapid_trigger: Option<RapidTrigger<i32>>,
+  pub deep_trigger: Option<DeepTrigger<i32>>,
+  pub press_samples: u8,
+  pub release_samples: u8,
 }
 
 impl Default for SwitchSettings {
@@ -29,10 +227,26 @@ impl Default for SwitchSettings {
       polarity: Polarity::S,
       trig_down: 0.1,
       trig_up: 0.4,
+      rapid_trigger: None,
+      deep_trigger: None,
+      range: None,
+      travel: None,
+      debounce: None,
     }
   }
 }
 
+// Actuation point for one key on one layer, or on all layers if no layer is
+// given. Layer overrides take priority over all-layer overrides.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActuationOverride {
+  pub key: KeyIndex,
+  #[serde(default)]
+  pub layer: Option<LayerIndex>,
+  pub trig_down: f32,
+  pub trig_up: f32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Board {
   // matrix (i,j) -> register (idx, bit)
@@ -42,6 +256,30 @@ pub struct Board {
   pub bus_pins: [PinIndex; BUS_WIDTH],
   pub sel_pins: [PinIndex; SEL_WIDTH],
   pub led_ind_pins: [PinIndex; 2],
+  // neopixel status colors
+  #[serde(default)]
+  pub status: StatusConfig,
+  // thresholds chosen by auto-calibration
+  #[serde(default)]
+  pub auto_calibration: CalibrationSettings,
+  // noise filtering of sensor readings
+  #[serde(default)]
+  pub filter: FilterSettings,
+  #[serde(default)]
+  pub drift: DriftSettings,
+  // for keys without their own
+  #[serde(default)]
+  pub debounce: Debounce,
+  #[serde(default)]
+  pub chatter: ChatterSettings,
+  #[serde(default)]
+  pub scan: ScanSettings,
+  // sensor health checks
+  #[serde(default)]
+  pub faults: FaultSettings,
+  // name of a profile in profiles/switches.json, for actuation points in mm
+  #[serde(default)]
+  pub switch_profile: Option<heapless::String<MAX_PROFILE_NAME>>,
 }
 
 pub struct BoardPins<Q: OutputPin> {
@@ -120,4 +358,31 @@ mod tests {
       .map_err(|e| format!("{}", e))?;
     Ok(())
   }
+
+  #[test]
+  fn fixed_thresholds_are_exact() {
+    for polarity in [Polarity::S, Polarity::N] {
+      let settings = SwitchSettings {
+        polarity,
+        trig_down: 0.1003,
+        trig_up: 0.25,
+        ..SwitchSettings::default()
+      };
+      let fixed = settings.to_fixed();
+      for value in 0..ADC_MAX {
+        let norm = value as f32 / ADC_MAX as f32;
+        let v = value as i32;
+        match polarity {
+          Polarity::S => {
+            assert_eq!(norm < settings.trig_down, v < fixed.trig_down);
+            assert_eq!(norm > settings.trig_up, v > fixed.trig_up);
+          }
+          Polarity::N => {
+            assert_eq!(norm > settings.trig_down, v > fixed.trig_down);
+            assert_eq!(norm < settings.trig_up, v < fixed.trig_up);
+          }
+        }
+      }
+    }
+  }
 }
@@ -0,0 +1,226 @@
+use serde::{Serialize, Deserialize};
+use heapless::Vec;
+
+use crate::board::{Polarity, SwitchRange, SwitchSettings};
+use crate::schedule::ScanSettings;
+use crate::prelude::*;
+
+// samples per register to average for the resting value, keys must be
+// released during this time
+pub const REST_SAMPLES: u32 = 256;
+// time a register's keys must read released before its resting value is
+// measured, so keys let go just before calibration have settled
+pub const SETTLE_MS: u32 = 500;
+// time before calibration finishes on its own
+pub const CALIBRATION_MS: u32 = 30_000;
+
+// Thresholds as fractions of each key's measured travel, from rest (0.0) to
+// bottom-out (1.0)
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CalibrationSettings {
+  pub trig_down: f32,
+  pub trig_up: f32,
+  // keys with less normalized travel than this are left uncalibrated
+  pub min_travel: f32,
+}
+
+impl Default for CalibrationSettings {
+  fn default() -> Self {
+    Self {
+      trig_down: 0.5,
+      trig_up: 0.4,
+      min_travel: 0.05,
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ChannelRange {
+  rest_sum: u32,
+  min: u16,
+  max: u16,
+}
+
+impl Default for ChannelRange {
+  fn default() -> Self {
+    Self { rest_sum: 0, min: u16::MAX, max: 0 }
+  }
+}
+
+// Result for one switch, values normalized as in SwitchSettings
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KeyRange {
+  pub polarity: Polarity,
+  pub rest: f32,
+  pub bottom: f32,
+}
+
+// Records the resting value and the full range of every switch
+pub struct Calibrator {
+  settings: CalibrationSettings,
+  ranges: Vec<[ChannelRange; BUS_WIDTH], MAX_REGS>,
+  // all samples per register, calibration ends after max_samples
+  samples: Vec<u32, MAX_REGS>,
+  // consecutive released samples per register while waiting to rest
+  settled: Vec<u32, MAX_REGS>,
+  // samples per register averaged into the resting value so far
+  rest_samples: Vec<u32, MAX_REGS>,
+  // CALIBRATION_MS and SETTLE_MS at the scan rate
+  max_samples: u32,
+  settle_samples: u32,
+}
+
+impl Calibrator {
+  pub fn new(num_regs: usize, settings: CalibrationSettings, scan: &ScanSettings) -> Self {
+    let mut ranges = Vec::new();
+    ranges.resize_default(num_regs).unwrap();
+    let mut samples = Vec::new();
+    samples.resize_default(num_regs).unwrap();
+    let mut settled = Vec::new();
+    settled.resize_default(num_regs).unwrap();
+    let mut rest_samples = Vec::new();
+    rest_samples.resize_default(num_regs).unwrap();
+    Self {
+      settings, ranges, samples, settled, rest_samples,
+      max_samples: scan.samples(CALIBRATION_MS),
+      settle_samples: scan.samples(SETTLE_MS),
+    }
+  }
+
+  // `is_released` tells whether every key of the register reads released by
+  // its current thresholds. The resting value is only measured once they
+  // have for SETTLE_MS, and starts over if a key is pressed before it is
+  // complete.
+  pub fn sample(&mut self, i_reg: usize, values: RegValue, is_released: bool) {
+    self.samples[i_reg] = self.samples[i_reg].saturating_add(1);
+    let ranges = &mut self.ranges[i_reg];
+    if self.rest_samples[i_reg] < REST_SAMPLES {
+      if !is_released {
+        self.settled[i_reg] = 0;
+        self.rest_samples[i_reg] = 0;
+        ranges.iter_mut().for_each(|range| range.rest_sum = 0);
+      }
+      else if self.settled[i_reg] < self.settle_samples {
+        self.settled[i_reg] += 1;
+      }
+      else {
+        for (range, &value) in ranges.iter_mut().zip(values.iter()) {
+          range.rest_sum += value as u32;
+        }
+        self.rest_samples[i_reg] += 1;
+      }
+      return;
+    }
+    for (range, &value) in ranges.iter_mut().zip(values.iter()) {
+      range.min = range.min.min(value);
+      range.max = range.max.max(value);
+    }
+  }
+
+  pub fn is_resting(&self) -> bool {
+    self.rest_samples.iter().any(|&n| n < REST_SAMPLES)
+  }
+
+  pub fn is_done(&self) -> bool {
+    self.samples.iter().all(|&n| n >= self.max_samples)
+  }
+
+  // Polarity follows the direction the value moved furthest from rest.
+  pub fn range(&self, i_reg: usize, bit: usize) -> Option<KeyRange> {
+    let range = &self.ranges[i_reg][bit];
+    // no resting value, or no samples after it
+    if self.rest_samples[i_reg] < REST_SAMPLES || range.min > range.max {
+      return None;
+    }
+    let rest = (range.rest_sum / REST_SAMPLES) as u16;
+    let fall = rest.saturating_sub(range.min);
+    let rise = range.max.saturating_sub(rest);
+    let (polarity, bottom) = if fall >= rise {
+      (Polarity::S, range.min)
+    }
+    else {
+      (Polarity::N, range.max)
+    };
+    let key_range = KeyRange {
+      polarity,
+      rest: rest as f32 / ADC_MAX as f32,
+      bottom: bottom as f32 / ADC_MAX as f32,
+    };
+    if (key_range.bottom - key_range.rest).abs() < self.settings.min_travel {
+      return None;
+    }
+    Some(key_range)
+  }
+
+  // thresholds placed along the measured travel, other settings from `base`
+  pub fn switch_settings(
+    &self, i_reg: usize, bit: usize, base: SwitchSettings) -> Option<SwitchSettings>
+  {
+    let range = self.range(i_reg, bit)?;
+    let travel = range.bottom - range.rest;
+    Some(SwitchSettings {
+      polarity: range.polarity,
+      trig_down: range.rest + travel * self.settings.trig_down,
+      trig_up: range.rest + travel * self.settings.trig_up,
+      range: Some(SwitchRange { rest: range.rest, bottom: range.bottom }),
+      ..base
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn detects_polarity_and_thresholds() {
+    let scan = ScanSettings::default();
+    let mut cal = Calibrator::new(1, CalibrationSettings::default(), &scan);
+    for _ in 0..scan.samples(SETTLE_MS) + REST_SAMPLES - 1 {
+      cal.sample(0, [2048, 2048, 2048, 2048], true);
+    }
+    assert!(cal.is_resting());
+    cal.sample(0, [2048, 2048, 2048, 2048], true);
+    assert!(!cal.is_resting());
+    assert!(cal.range(0, 0).is_none());
+    // S falls, N rises, the last two barely move
+    cal.sample(0, [1024, 3072, 2000, 2100], true);
+    let s = cal.switch_settings(0, 0, SwitchSettings::default()).unwrap();
+    assert!(matches!(s.polarity, Polarity::S));
+    assert_eq!((s.trig_down, s.trig_up), (0.375, 0.4));
+    assert!(s.is_valid());
+    let n = cal.switch_settings(0, 1, SwitchSettings::default()).unwrap();
+    assert!(matches!(n.polarity, Polarity::N));
+    assert_eq!((n.trig_down, n.trig_up), (0.625, 0.6));
+    assert!(n.is_valid());
+    assert!(cal.range(0, 2).is_none());
+    assert!(cal.range(0, 3).is_none());
+  }
+
+  #[test]
+  fn rest_waits_for_released_keys() {
+    let scan = ScanSettings::default();
+    let mut cal = Calibrator::new(1, CalibrationSettings::default(), &scan);
+    // the first key is still held when calibration starts, and is released
+    // partway through what would have been the rest phase
+    for _ in 0..REST_SAMPLES / 2 {
+      cal.sample(0, [1024, 2048, 2048, 2048], false);
+    }
+    // then pressed again halfway through measuring the rest, which starts over
+    for _ in 0..scan.samples(SETTLE_MS) + REST_SAMPLES / 2 {
+      cal.sample(0, [2048, 2048, 2048, 2048], true);
+    }
+    cal.sample(0, [1024, 2048, 2048, 2048], false);
+    for _ in 0..scan.samples(SETTLE_MS) + REST_SAMPLES - 1 {
+      cal.sample(0, [2048, 2048, 2048, 2048], true);
+    }
+    assert!(cal.is_resting());
+    cal.sample(0, [2048, 2048, 2048, 2048], true);
+    assert!(!cal.is_resting());
+    cal.sample(0, [1024, 2048, 2048, 2048], true);
+    // the held samples are not part of the resting value
+    let range = cal.range(0, 0).unwrap();
+    assert_eq!((range.rest, range.bottom), (0.5, 0.25));
+  }
+}
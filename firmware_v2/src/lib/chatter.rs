@@ -0,0 +1,149 @@
+// Also in firmware/src/lib/chatter.rs, the two firmwares are built
+// separately. Keep the copies in step.
+use serde::{Serialize, Deserialize};
+use heapless::HistoryBuffer;
+
+use crate::prelude::*;
+
+// most recent chatter events kept with their timestamps
+pub const CHATTER_LOG_LEN: usize = 16;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatterSettings {
+  // a press this soon after the previous press of the same key, with a
+  // release in between, is counted as chatter. Deliberate repeated presses
+  // of one key are rarely faster than 10 per second.
+  pub window_ms: u32,
+}
+
+impl Default for ChatterSettings {
+  fn default() -> Self {
+    Self { window_ms: 50 }
+  }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct KeyChatter {
+  pub count: u32,
+  pub last_ms: Option<u32>,
+  // shortest time from press to press counted
+  pub min_interval_ms: Option<u32>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Chatter {
+  pub key: KeyIndex,
+  pub at_ms: u32,
+  pub interval_ms: u32,
+}
+
+// Counts suspiciously quick down-up-down sequences of each key
+pub struct ChatterDetector {
+  settings: ChatterSettings,
+  last_down_ms: [Option<u32>; MAX_KEYS],
+  stats: [KeyChatter; MAX_KEYS],
+  log: HistoryBuffer<Chatter, CHATTER_LOG_LEN>,
+}
+
+impl ChatterDetector {
+  pub fn new(settings: ChatterSettings) -> Self {
+    Self {
+      settings,
+      last_down_ms: [None; MAX_KEYS],
+      stats: [KeyChatter::default(); MAX_KEYS],
+      log: HistoryBuffer::new(),
+    }
+  }
+
+  pub fn settings(&self) -> ChatterSettings {
+    self.settings
+  }
+
+  // Record a change of a key, returns the chatter it completes, if any.
+  pub fn update(&mut self, key: KeyIndex, is_down: bool, now_ms: u32) -> Option<Chatter> {
+    if !is_down {
+      return None;
+    }
+    let idx = key as usize;
+    let last_ms = self.last_down_ms[idx].replace(now_ms)?;
+    let interval_ms = now_ms.wrapping_sub(last_ms);
+    if interval_ms > self.settings.window_ms {
+      return None;
+    }
+    let stats = &mut self.stats[idx];
+    stats.count = stats.count.saturating_add(1);
+    stats.last_ms = Some(now_ms);
+    stats.min_interval_ms = Some(stats.min_interval_ms.map_or(interval_ms, |m| m.min(interval_ms)));
+    let chatter = Chatter { key, at_ms: now_ms, interval_ms };
+    self.log.write(chatter);
+    Some(chatter)
+  }
+
+  pub fn key_stats(&self, key: KeyIndex) -> KeyChatter {
+    self.stats[key as usize]
+  }
+
+  pub fn total(&self) -> u32 {
+    self.stats.iter().map(|s| s.count).fold(0, u32::saturating_add)
+  }
+
+  // oldest first
+  pub fn recent(&self) -> impl Iterator<Item=&Chatter> {
+    self.log.oldest_ordered()
+  }
+
+  pub fn reset(&mut self) {
+    self.stats = [KeyChatter::default(); MAX_KEYS];
+    self.log.clear();
+  }
+
+  // Per-key counts and the most recent events, times in ms since boot
+  pub fn report(&self, write_fmt: impl Fn(core::fmt::Arguments)) {
+    for (key, stats) in self.stats.iter().enumerate() {
+      if let (Some(last_ms), Some(min_interval_ms)) = (stats.last_ms, stats.min_interval_ms) {
+        write_fmt(format_args!(
+          "key {}: {} times, last at {} ms, shortest {} ms\r\n",
+          key, stats.count, last_ms, min_interval_ms));
+      }
+    }
+    for chatter in self.recent() {
+      write_fmt(format_args!(
+        "recent: key {} at {} ms after {} ms\r\n",
+        chatter.key, chatter.at_ms, chatter.interval_ms));
+    }
+    write_fmt(format_args!(
+      "{} chatter events within {} ms.\r\n", self.total(), self.settings.window_ms));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_quick_repeats() {
+    let mut detector = ChatterDetector::new(ChatterSettings { window_ms: 50 });
+    let changes = [
+      (3, true, 100), (3, false, 120), (3, true, 140), (3, false, 240),
+      // a deliberate second press
+      (3, true, 400), (3, false, 450),
+      (5, true, 1000), (5, false, 1002), (5, true, 1010), (5, false, 1011), (5, true, 1013),
+    ];
+    let found: std::vec::Vec<Chatter> = changes.iter()
+      .filter_map(|&(key, is_down, now_ms)| detector.update(key, is_down, now_ms))
+      .collect();
+    assert_eq!(found, [
+      Chatter { key: 3, at_ms: 140, interval_ms: 40 },
+      Chatter { key: 5, at_ms: 1010, interval_ms: 10 },
+      Chatter { key: 5, at_ms: 1013, interval_ms: 3 },
+    ][..]);
+    assert_eq!(detector.key_stats(5), KeyChatter {
+      count: 2, last_ms: Some(1013), min_interval_ms: Some(3),
+    });
+    assert_eq!(detector.total(), 3);
+    detector.reset();
+    assert_eq!(detector.total(), 0);
+    assert_eq!(detector.recent().count(), 0);
+  }
+}
@@ -0,0 +1,327 @@
+use core::fmt;
+use core::str;
+use ehal::digital::v2::OutputPin;
+use heapless::Vec;
+
+use crate::board::ActuationOverride;
+use crate::switch_matrix::SwitchMatrix;
+use crate::vkeyboard::VKeyboard;
+use crate::event_queue::EventQueue;
+use crate::settings::{Flash, Settings, SettingsStore};
+use crate::clock::Clock;
+use crate::prelude::*;
+
+pub const MAX_LINE: usize = 64;
+
+// Collects serial input into lines
+#[derive(Default)]
+pub struct LineBuffer {
+  buf: Vec<u8, MAX_LINE>,
+}
+
+impl LineBuffer {
+  pub fn new() -> Self {
+    Self { buf: Vec::new() }
+  }
+
+  // returns the line once a CR or LF ends it, overlong lines are truncated
+  pub fn push(&mut self, byte: u8) -> Option<Vec<u8, MAX_LINE>> {
+    match byte {
+      b'\r' | b'\n' => {
+        if self.buf.is_empty() {
+          return None;
+        }
+        let line = self.buf.clone();
+        self.buf.clear();
+        Some(line)
+      }
+      // backspace, delete
+      0x08 | 0x7f => {
+        self.buf.pop();
+        None
+      }
+      _ => {
+        self.buf.push(byte).ok();
+        None
+      }
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Command {
+  Help,
+  // show the active settings of a key
+  Get(KeyIndex),
+  // list all actuation overrides
+  List,
+  Set(ActuationOverride),
+  Clear(KeyIndex, Option<LayerIndex>),
+  CalibrateStart,
+  CalibrateDone,
+  // measure the noise floor
+  Noise,
+  // report scan times since the last report
+  Scan,
+  // time fixed-point against float threshold updates
+  #[cfg(any(test, feature = "bench"))]
+  Bench,
+  // show or clear chatter statistics
+  Chatter,
+  ChatterReset,
+  // show or clear key event queue statistics
+  Events,
+  EventsReset,
+  // list keys disabled by sensor faults
+  Faults,
+  Enable(KeyIndex),
+  // set the default layer
+  Layer(LayerIndex),
+  Save,
+  // erase stored settings
+  Defaults,
+}
+
+const HELP: &str = "\
+help                          show this message\r\n\
+get <key>                     show key settings\r\n\
+list                          list actuation overrides\r\n\
+set <key> <down> <up> [layer] override actuation points\r\n\
+clear <key> [layer]           remove an override\r\n\
+cal start                     start auto-calibration\r\n\
+cal done                      finish auto-calibration\r\n\
+noise                         measure sensor noise\r\n\
+scan                          show scan times\r\n\
+chatter [reset]               show or clear chatter counts\r\n\
+events [reset]                show or clear event queue use\r\n\
+faults                        list faulty keys\r\n\
+enable <key>                  re-enable a faulty key\r\n\
+layer <layer>                 set the default layer\r\n\
+save                          store settings in flash\r\n\
+defaults                      erase stored settings\r\n";
+
+#[cfg(any(test, feature = "bench"))]
+const HELP_BENCH: &str = "\
+bench                         time fixed against float thresholds\r\n";
+
+const HELP_NOTES: &str = "\
+\r\n\
+set, clear and layer are stored immediately, calibration when it finishes.\r\n";
+
+fn parse_arg<'a, T: str::FromStr>(
+  args: &mut impl Iterator<Item=&'a str>, err: &'static str)
+  -> Result<T, &'static str>
+{
+  args.next().ok_or(err)?.parse::<T>().map_err(|_| err)
+}
+
+fn parse_opt_arg<'a, T: str::FromStr>(
+  args: &mut impl Iterator<Item=&'a str>, err: &'static str)
+  -> Result<Option<T>, &'static str>
+{
+  match args.next() {
+    Some(arg) => Ok(Some(arg.parse::<T>().map_err(|_| err)?)),
+    None => Ok(None),
+  }
+}
+
+pub fn parse_command(line: &[u8]) -> Result<Command, &'static str> {
+  let line = str::from_utf8(line).map_err(|_| "invalid utf8")?;
+  let mut args = line.split_ascii_whitespace();
+  let cmd = match args.next().ok_or("empty command")? {
+    "help" => Command::Help,
+    "get" => Command::Get(parse_arg(&mut args, "invalid key")?),
+    "list" => Command::List,
+    "set" => Command::Set(ActuationOverride {
+      key: parse_arg(&mut args, "invalid key")?,
+      trig_down: parse_arg(&mut args, "invalid trig_down")?,
+      trig_up: parse_arg(&mut args, "invalid trig_up")?,
+      layer: parse_opt_arg(&mut args, "invalid layer")?,
+    }),
+    "clear" => Command::Clear(
+      parse_arg(&mut args, "invalid key")?,
+      parse_opt_arg(&mut args, "invalid layer")?,
+    ),
+    "cal" => match args.next() {
+      Some("start") => Command::CalibrateStart,
+      Some("done") => Command::CalibrateDone,
+      _ => return Err("expected cal start or cal done"),
+    },
+    "noise" => Command::Noise,
+    "scan" => Command::Scan,
+    #[cfg(any(test, feature = "bench"))]
+    "bench" => Command::Bench,
+    "chatter" => match args.next() {
+      None => Command::Chatter,
+      Some("reset") => Command::ChatterReset,
+      _ => return Err("expected chatter or chatter reset"),
+    },
+    "events" => match args.next() {
+      None => Command::Events,
+      Some("reset") => Command::EventsReset,
+      _ => return Err("expected events or events reset"),
+    },
+    "faults" => Command::Faults,
+    "enable" => Command::Enable(parse_arg(&mut args, "invalid key")?),
+    "layer" => Command::Layer(parse_arg(&mut args, "invalid layer")?),
+    "save" => Command::Save,
+    "defaults" => Command::Defaults,
+    _ => return Err("unknown command, try help"),
+  };
+  if args.next().is_some() {
+    return Err("too many arguments");
+  }
+  Ok(cmd)
+}
+
+pub fn save_settings<Q: OutputPin, F: Flash>(
+  switches: &SwitchMatrix<Q>, vkbd: &VKeyboard, store: &mut SettingsStore<F>,
+  write_fmt: impl Fn(fmt::Arguments) -> ())
+{
+  match store.save(&Settings::capture(switches, vkbd)) {
+    Ok(()) => write_fmt(format_args!("saved\r\n")),
+    Err(err) => write_fmt(format_args!("error saving settings: {:?}\r\n", err)),
+  }
+}
+
+pub fn run_command<Q: OutputPin, F: Flash, C: Clock>(
+  cmd: Command, switches: &mut SwitchMatrix<Q>, events: &mut EventQueue, vkbd: &mut VKeyboard,
+  store: &mut SettingsStore<F>, clock: &C, write_fmt: impl Fn(fmt::Arguments) -> ())
+{
+  // only the bench needs the clock
+  #[cfg(not(any(test, feature = "bench")))]
+  let _ = clock;
+  match cmd {
+    Command::Help => {
+      write_fmt(format_args!("{}", HELP));
+      #[cfg(any(test, feature = "bench"))]
+      write_fmt(format_args!("{}", HELP_BENCH));
+      write_fmt(format_args!("{}", HELP_NOTES));
+    }
+    Command::Get(key) => match switches.settings(key) {
+      Some(settings) => {
+        write_fmt(format_args!(
+          "key {}: {:?} down {:.3} up {:.3} drift {:.4}\r\n",
+          key, settings.polarity, settings.trig_down, settings.trig_up,
+          switches.drift(key).unwrap_or(0.0)));
+        let profile = switches.profile();
+        let down_mm = profile.and_then(|p| p.reading_to_mm(&settings, settings.trig_down));
+        let up_mm = profile.and_then(|p| p.reading_to_mm(&settings, settings.trig_up));
+        if let (Some(down_mm), Some(up_mm)) = (down_mm, up_mm) {
+          write_fmt(format_args!("  down {:.2} mm up {:.2} mm\r\n", down_mm, up_mm));
+        }
+      }
+      None => write_fmt(format_args!("no key {}\r\n", key)),
+    },
+    Command::List => {
+      for ov in switches.overrides() {
+        match ov.layer {
+          Some(layer) => write_fmt(format_args!(
+            "key {} layer {}: down {:.3} up {:.3}\r\n",
+            ov.key, layer, ov.trig_down, ov.trig_up)),
+          None => write_fmt(format_args!(
+            "key {}: down {:.3} up {:.3}\r\n",
+            ov.key, ov.trig_down, ov.trig_up)),
+        }
+      }
+    }
+    Command::Set(ov) => match switches.set_override(ov) {
+      Ok(()) => save_settings(switches, vkbd, store, write_fmt),
+      Err(err) => write_fmt(format_args!("error: {:?}\r\n", err)),
+    },
+    Command::Clear(key, layer) => {
+      if switches.clear_override(key, layer) {
+        save_settings(switches, vkbd, store, write_fmt);
+      }
+      else {
+        write_fmt(format_args!("no override\r\n"));
+      }
+    }
+    Command::CalibrateStart => switches.start_calibration(write_fmt),
+    Command::CalibrateDone => {
+      if switches.is_calibrating() {
+        switches.finish_calibration(write_fmt);
+      }
+      else {
+        write_fmt(format_args!("not calibrating\r\n"));
+      }
+    }
+    Command::Noise => switches.start_noise_measurement(write_fmt),
+    Command::Scan => {
+      let scan_time = core::mem::take(&mut switches.scan_time);
+      match scan_time.mean_us() {
+        Some(mean_us) => write_fmt(format_args!(
+          "{} scans: mean {} us ({} us per register, {} Hz) min {} us max {} us\r\n",
+          scan_time.scans, mean_us, mean_us / switches.num_regs().max(1) as u32,
+          1_000_000 / mean_us.max(1), scan_time.min_us, scan_time.max_us)),
+        None => write_fmt(format_args!("no scans timed\r\n")),
+      }
+      if let Some(mean_late_us) = scan_time.mean_late_us() {
+        write_fmt(format_args!(
+          "started late by mean {} us max {} us, {} scans missed\r\n",
+          mean_late_us, scan_time.max_late_us, scan_time.missed));
+      }
+    }
+    #[cfg(any(test, feature = "bench"))]
+    Command::Bench => switches.bench(clock, write_fmt),
+    Command::Chatter => switches.chatter().report(write_fmt),
+    Command::ChatterReset => {
+      switches.reset_chatter();
+      write_fmt(format_args!("ok\r\n"));
+    }
+    Command::Events => events.report(write_fmt),
+    Command::EventsReset => {
+      events.reset_stats();
+      write_fmt(format_args!("ok\r\n"));
+    }
+    Command::Faults => {
+      for (key, fault) in switches.faults() {
+        write_fmt(format_args!("key {}: {:?}\r\n", key, fault));
+      }
+    }
+    Command::Enable(key) => match switches.enable(key) {
+      Ok(()) => write_fmt(format_args!("ok\r\n")),
+      Err(err) => write_fmt(format_args!("error: {:?}\r\n", err)),
+    },
+    Command::Layer(layer) => match vkbd.set_default_layer(layer) {
+      Ok(()) => save_settings(switches, vkbd, store, write_fmt),
+      Err(err) => write_fmt(format_args!("error: {:?}\r\n", err)),
+    },
+    Command::Save => save_settings(switches, vkbd, store, write_fmt),
+    Command::Defaults => {
+      store.clear();
+      write_fmt(format_args!("stored settings erased, defaults apply after reset\r\n"));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_commands() {
+    assert_eq!(parse_command(b"get 12"), Ok(Command::Get(12)));
+    assert_eq!(parse_command(b" set 3 0.2 0.25 1 "), Ok(Command::Set(ActuationOverride {
+      key: 3, layer: Some(1), trig_down: 0.2, trig_up: 0.25,
+    })));
+    assert_eq!(parse_command(b"clear 3"), Ok(Command::Clear(3, None)));
+    assert_eq!(parse_command(b"set 3 0.2"), Err("invalid trig_up"));
+    assert_eq!(parse_command(b"get 1 2"), Err("too many arguments"));
+    assert_eq!(parse_command(b"layer 2"), Ok(Command::Layer(2)));
+    assert_eq!(parse_command(b"bench"), Ok(Command::Bench));
+  }
+
+  #[test]
+  fn line_buffer() {
+    let mut lines = LineBuffer::new();
+    let mut out = None;
+    for &b in b"gex\x7ft 1\r\n" {
+      if let Some(line) = lines.push(b) {
+        assert!(out.is_none());
+        out = Some(line);
+      }
+    }
+    assert_eq!(&out.unwrap()[..], b"get 1");
+  }
+}
@@ -3,5 +3,10 @@ pub enum Error {
   PinConfigError,
   UsbError,
   VecOverflow,
+  QueueOverflow,
   SizeMismatch,
+  InvalidKey,
+  InvalidSettings,
+  SerializeError,
+  FlashError,
 }
@@ -0,0 +1,111 @@
+use heapless::Deque;
+use core::fmt;
+
+use crate::vkeyboard::KeyEvent;
+use crate::prelude::*;
+
+// Key events waiting to be processed, oldest first. Scanning pushes and key
+// processing pops, so processing can lag behind or hold events back, e.g. to
+// decide between a tap and a hold.
+pub struct EventQueue {
+  events: Deque<KeyEvent, MAX_EVENTS>,
+  // events dropped because the queue was full
+  dropped: u32,
+  // most events queued at once
+  peak: usize,
+}
+
+impl EventQueue {
+  pub fn new() -> Self {
+    Self { events: Deque::new(), dropped: 0, peak: 0 }
+  }
+
+  // A full queue drops the new event. Dropped events are counted, since a
+  // lost release leaves a key held until it is pressed again.
+  pub fn push(&mut self, event: KeyEvent) -> Result<(), Error> {
+    match self.events.push_back(event) {
+      Ok(()) => {
+        self.peak = self.peak.max(self.events.len());
+        Ok(())
+      }
+      Err(_) => {
+        self.dropped = self.dropped.saturating_add(1);
+        Err(Error::QueueOverflow)
+      }
+    }
+  }
+
+  pub fn pop(&mut self) -> Option<KeyEvent> {
+    self.events.pop_front()
+  }
+
+  // oldest event, left in the queue
+  pub fn peek(&self) -> Option<&KeyEvent> {
+    self.events.front()
+  }
+
+  pub fn len(&self) -> usize {
+    self.events.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.events.is_empty()
+  }
+
+  pub fn dropped(&self) -> u32 {
+    self.dropped
+  }
+
+  pub fn peak(&self) -> usize {
+    self.peak
+  }
+
+  pub fn reset_stats(&mut self) {
+    self.dropped = 0;
+    self.peak = self.events.len();
+  }
+
+  pub fn report(&self, write_fmt: impl Fn(fmt::Arguments) -> ()) {
+    write_fmt(format_args!(
+      "{} of {} events queued, peak {}, {} dropped\r\n",
+      self.len(), MAX_EVENTS, self.peak, self.dropped));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vkeyboard::KeyChange;
+
+  fn down(key: KeyIndex) -> KeyEvent {
+    KeyEvent { change: KeyChange::Down(key), time: key as u64 }
+  }
+
+  fn key(event: Option<KeyEvent>) -> Option<KeyIndex> {
+    match event?.change {
+      KeyChange::Down(key) => Some(key),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn drops_and_counts_overflow() {
+    let mut queue = EventQueue::new();
+    for i in 0..MAX_EVENTS {
+      queue.push(down(i as KeyIndex)).unwrap();
+    }
+    assert!(queue.push(down(100)).is_err());
+    assert_eq!(queue.dropped(), 1);
+    assert_eq!(queue.peak(), MAX_EVENTS);
+    assert_eq!(key(queue.peek().copied()), Some(0));
+    assert_eq!(key(queue.pop()), Some(0));
+    queue.push(down(101)).unwrap();
+    for i in 1..MAX_EVENTS {
+      assert_eq!(key(queue.pop()), Some(i as KeyIndex));
+    }
+    assert_eq!(key(queue.pop()), Some(101));
+    assert!(queue.is_empty());
+    queue.reset_stats();
+    assert_eq!((queue.dropped(), queue.peak()), (0, 0));
+  }
+}
@@ -0,0 +1,293 @@
+use serde::{Serialize, Deserialize};
+use heapless::Vec;
+
+use crate::bus::{AnalogBus, BusReading};
+use crate::schedule::ScanSettings;
+use crate::prelude::*;
+
+// duration of a noise measurement
+pub const NOISE_MS: u32 = 2_000;
+// fractional bits of the moving average
+const EMA_BITS: u32 = 16;
+const EMA_ONE: u32 = 1 << EMA_BITS;
+
+// Noise filtering applied to every channel before thresholds, in order:
+// oversampling, median of three, exponential smoothing. The defaults pass
+// single reads through unchanged.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterSettings {
+  // bus reads averaged into one sample
+  pub oversample: u8,
+  // median of the last three samples, removes single-sample spikes
+  pub median: bool,
+  // weight of a new sample in the moving average, 1.0 disables smoothing
+  pub ema_alpha: f32,
+}
+
+impl FilterSettings {
+  // No reads, or a moving average that never moves, would leave every key
+  // stuck, so those are errors rather than clamped.
+  pub fn to_fixed(&self) -> Result<FixedFilterSettings, Error> {
+    if self.oversample == 0 || !(self.ema_alpha > 0.0 && self.ema_alpha <= 1.0) {
+      return Err(Error::InvalidSettings);
+    }
+    Ok(FixedFilterSettings {
+      oversample: self.oversample as u32,
+      median: self.median,
+      ema_alpha: (self.ema_alpha * EMA_ONE as f32) as u32,
+    })
+  }
+}
+
+// FilterSettings for the scan loop, with the EMA weight in 1/EMA_ONE
+#[derive(Debug, Copy, Clone)]
+pub struct FixedFilterSettings {
+  pub oversample: u32,
+  pub median: bool,
+  pub ema_alpha: u32,
+}
+
+impl Default for FilterSettings {
+  fn default() -> Self {
+    Self {
+      oversample: 1,
+      median: false,
+      ema_alpha: 1.0,
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct ChannelFilter {
+  // last three oversampled values, newest first
+  history: [u16; 3],
+  // in 1/EMA_ONE counts
+  ema: i32,
+  primed: bool,
+}
+
+impl ChannelFilter {
+  fn update(&mut self, value: u16, settings: &FixedFilterSettings) -> u16 {
+    if !self.primed {
+      self.history = [value; 3];
+      self.ema = (value as i32) << EMA_BITS;
+      self.primed = true;
+    }
+    self.history = [value, self.history[0], self.history[1]];
+    let value = if settings.median {
+      let [a, b, c] = self.history;
+      a.max(b).min(a.min(b).max(c))
+    }
+    else {
+      value
+    };
+    let diff = ((value as i32) << EMA_BITS) - self.ema;
+    self.ema += ((diff as i64 * settings.ema_alpha as i64) >> EMA_BITS) as i32;
+    ((self.ema + (EMA_ONE as i32 >> 1)) >> EMA_BITS) as u16
+  }
+}
+
+// Filter state for the channels of one register
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RegFilter {
+  channels: [ChannelFilter; BUS_WIDTH],
+}
+
+impl RegFilter {
+  // Returns the last unfiltered bus reading, for checks that need to see the
+  // sensor itself, and the filtered values. Failed conversions are left out
+  // of the average. A channel without any good reading repeats its previous
+  // one.
+  pub fn read<B: AnalogBus>(
+    &mut self, bus: &mut B, settings: &FixedFilterSettings) -> (BusReading, RegValue)
+  {
+    let mut sums = [0u32; BUS_WIDTH];
+    let mut counts = [0u32; BUS_WIDTH];
+    let mut raw: BusReading = [None; BUS_WIDTH];
+    for _ in 0..settings.oversample {
+      raw = bus.read();
+      for (i, value) in raw.into_iter().enumerate() {
+        if let Some(value) = value {
+          sums[i] += value as u32;
+          counts[i] += 1;
+        }
+      }
+    }
+    let mut values: RegValue = [0; BUS_WIDTH];
+    for i in 0..BUS_WIDTH {
+      let n = counts[i];
+      let mean = if n > 0 {
+        ((sums[i] + n / 2) / n) as u16
+      }
+      else {
+        self.channels[i].history[0]
+      };
+      values[i] = self.channels[i].update(mean, settings);
+    }
+    (raw, values)
+  }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ChannelStats {
+  sum: u64,
+  sum_sq: u64,
+  min: u16,
+  max: u16,
+}
+
+impl Default for ChannelStats {
+  fn default() -> Self {
+    Self { sum: 0, sum_sq: 0, min: u16::MAX, max: 0 }
+  }
+}
+
+// no float sqrt without std
+fn isqrt(n: u64) -> u64 {
+  let mut x = 0;
+  let mut bit = 1u64 << 62;
+  let mut rem = n;
+  while bit > n {
+    bit >>= 2;
+  }
+  while bit != 0 {
+    if rem >= x + bit {
+      rem -= x + bit;
+      x = (x >> 1) + bit;
+    }
+    else {
+      x >>= 1;
+    }
+    bit >>= 2;
+  }
+  x
+}
+
+// Spread of one channel in ADC counts
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Noise {
+  pub mean: f32,
+  pub std_dev: f32,
+  pub peak_to_peak: u16,
+}
+
+// Collects the spread of filtered readings, with all keys at rest this is
+// the noise floor that the hysteresis band has to clear
+pub struct NoiseMeter {
+  stats: Vec<[ChannelStats; BUS_WIDTH], MAX_REGS>,
+  samples: Vec<u32, MAX_REGS>,
+  // samples per register for NOISE_MS at the scan rate
+  max_samples: u32,
+}
+
+impl NoiseMeter {
+  pub fn new(num_regs: usize, scan: &ScanSettings) -> Self {
+    let mut stats = Vec::new();
+    stats.resize_default(num_regs).unwrap();
+    let mut samples = Vec::new();
+    samples.resize_default(num_regs).unwrap();
+    Self { stats, samples, max_samples: scan.samples(NOISE_MS) }
+  }
+
+  pub fn sample(&mut self, i_reg: usize, values: RegValue) {
+    if self.samples[i_reg] >= self.max_samples {
+      return;
+    }
+    for (stats, &value) in self.stats[i_reg].iter_mut().zip(values.iter()) {
+      stats.sum += value as u64;
+      stats.sum_sq += value as u64 * value as u64;
+      stats.min = stats.min.min(value);
+      stats.max = stats.max.max(value);
+    }
+    self.samples[i_reg] += 1;
+  }
+
+  pub fn is_done(&self) -> bool {
+    self.samples.iter().all(|&n| n >= self.max_samples)
+  }
+
+  pub fn noise(&self, i_reg: usize, bit: usize) -> Option<Noise> {
+    let n = self.samples[i_reg] as u64;
+    if n == 0 {
+      return None;
+    }
+    let stats = &self.stats[i_reg][bit];
+    // exact in integers, n * sum_sq stays well within u64
+    let var_n2 = n * stats.sum_sq - stats.sum * stats.sum;
+    Some(Noise {
+      mean: stats.sum as f32 / n as f32,
+      std_dev: isqrt(var_n2) as f32 / n as f32,
+      peak_to_peak: stats.max - stats.min,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct SeqBus<'a> {
+    values: &'a [u16],
+    i: usize,
+  }
+
+  // u16::MAX stands in for a failed conversion
+  impl AnalogBus for SeqBus<'_> {
+    fn read(&mut self) -> BusReading {
+      let value = self.values[self.i % self.values.len()];
+      self.i += 1;
+      [Some(value).filter(|&v| v != u16::MAX); BUS_WIDTH]
+    }
+  }
+
+  #[test]
+  fn oversample_and_median() {
+    let settings = FilterSettings { oversample: 2, median: true, ema_alpha: 1.0 }.to_fixed().unwrap();
+    let mut filter = RegFilter::default();
+    // pairs average to 100, 101, 3000 (a spike), 102, 103
+    let mut bus = SeqBus { values: &[99, 101, 100, 102, 2999, 3001, 101, 103, 102, 104], i: 0 };
+    let out: [u16; 5] = core::array::from_fn(|_| filter.read(&mut bus, &settings).1[0]);
+    assert_eq!(out, [100, 100, 101, 102, 103]);
+  }
+
+  #[test]
+  fn ema_smooths_steps() {
+    let settings = FilterSettings { ema_alpha: 0.5, ..Default::default() }.to_fixed().unwrap();
+    let mut filter = RegFilter::default();
+    let mut bus = SeqBus { values: &[1000], i: 0 };
+    assert_eq!(filter.read(&mut bus, &settings).1[0], 1000);
+    bus.values = &[2000];
+    let out: [u16; 3] = core::array::from_fn(|_| filter.read(&mut bus, &settings).1[0]);
+    assert_eq!(out, [1500, 1750, 1875]);
+  }
+
+  #[test]
+  fn drops_failed_conversions() {
+    let settings = FilterSettings { oversample: 2, ..Default::default() }.to_fixed().unwrap();
+    let mut filter = RegFilter::default();
+    let mut bus = SeqBus { values: &[100, 102, u16::MAX, 104, u16::MAX, u16::MAX], i: 0 };
+    let out: [u16; 3] = core::array::from_fn(|_| filter.read(&mut bus, &settings).1[0]);
+    // averaged without the failed read, then held when both failed
+    assert_eq!(out, [101, 104, 104]);
+  }
+
+  #[test]
+  fn rejects_stuck_filters() {
+    assert!(FilterSettings { oversample: 0, ..Default::default() }.to_fixed().is_err());
+    assert!(FilterSettings { ema_alpha: 0.0, ..Default::default() }.to_fixed().is_err());
+    assert!(FilterSettings { ema_alpha: f32::NAN, ..Default::default() }.to_fixed().is_err());
+  }
+
+  #[test]
+  fn noise_floor() {
+    let scan = ScanSettings::default();
+    let mut meter = NoiseMeter::new(1, &scan);
+    for i in 0..scan.samples(NOISE_MS) {
+      meter.sample(0, [2048, 2048 + (i % 2) as u16 * 4, 2048, 2048]);
+    }
+    assert!(meter.is_done());
+    assert_eq!(meter.noise(0, 0), Some(Noise { mean: 2048.0, std_dev: 0.0, peak_to_peak: 0 }));
+    assert_eq!(meter.noise(0, 1), Some(Noise { mean: 2050.0, std_dev: 2.0, peak_to_peak: 4 }));
+  }
+}
@@ -0,0 +1,155 @@
+use serde::{Serialize, Deserialize};
+
+use crate::schedule::ScanSettings;
+use crate::prelude::*;
+
+// samples over which slew violations are counted
+pub const SLEW_WINDOW: u32 = 1000;
+// raw samples per register for the boot self-test
+pub const SELF_TEST_SAMPLES: u32 = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Fault {
+  // stuck at ground, e.g. shorted or unpowered
+  RailLow,
+  // stuck at the supply
+  RailHigh,
+  // no noise at all, e.g. a disconnected mux channel
+  Flat,
+  // jumps faster than a key can move, e.g. a floating input
+  Slew,
+}
+
+// Thresholds for the sensor health checks, with durations converted to
+// samples per register at the scan rate. A live sensor always shows some
+// noise and never reaches the rails, but a key held at bottom-out can come
+// close, so the rail check is slow.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultSettings {
+  // readings within this of either rail, normalized
+  pub rail_margin: f32,
+  pub rail_ms: u32,
+  // identical readings in a row
+  pub flat_ms: u32,
+  // largest plausible change between samples, normalized
+  pub max_slew: f32,
+  // slew violations tolerated per SLEW_WINDOW samples
+  pub slew_events: u32,
+}
+
+impl FaultSettings {
+  pub fn to_fixed(&self, scan: &ScanSettings) -> FixedFaultSettings {
+    FixedFaultSettings {
+      rail_margin: (self.rail_margin * ADC_MAX as f32) as u16,
+      rail_samples: scan.samples(self.rail_ms),
+      flat_samples: scan.samples(self.flat_ms),
+      max_slew: (self.max_slew * ADC_MAX as f32) as u16,
+      slew_events: self.slew_events,
+    }
+  }
+}
+
+// FaultSettings with the margins in ADC counts and durations in samples
+#[derive(Debug, Copy, Clone)]
+pub struct FixedFaultSettings {
+  pub rail_margin: u16,
+  pub rail_samples: u32,
+  pub flat_samples: u32,
+  pub max_slew: u16,
+  pub slew_events: u32,
+}
+
+impl Default for FaultSettings {
+  fn default() -> Self {
+    Self {
+      rail_margin: 0.01,
+      rail_ms: 60_000,
+      flat_ms: 10_000,
+      max_slew: 0.25,
+      slew_events: 4,
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ChannelHealth {
+  last: Option<u16>,
+  rail_count: u32,
+  flat_count: u32,
+  // raised by SLEW_WINDOW per violation, lowered by one per sample
+  slew_score: u32,
+  pub fault: Option<Fault>,
+}
+
+impl ChannelHealth {
+  // Returns a newly detected fault, which is then kept until reset.
+  pub fn check(&mut self, value: u16, settings: &FixedFaultSettings) -> Option<Fault> {
+    if self.fault.is_some() {
+      return None;
+    }
+    let margin = settings.rail_margin;
+    let rail = if value <= margin {
+      Some(Fault::RailLow)
+    }
+    else if value >= ADC_MAX - 1 - margin {
+      Some(Fault::RailHigh)
+    }
+    else {
+      None
+    };
+    self.rail_count = if rail.is_some() { self.rail_count.saturating_add(1) } else { 0 };
+    let last = match self.last.replace(value) {
+      Some(last) => last,
+      None => return None,
+    };
+    self.flat_count = if value == last { self.flat_count.saturating_add(1) } else { 0 };
+    self.slew_score = self.slew_score.saturating_sub(1);
+    if value.abs_diff(last) > settings.max_slew {
+      self.slew_score = self.slew_score.saturating_add(SLEW_WINDOW);
+    }
+
+    self.fault = if self.rail_count >= settings.rail_samples {
+      rail
+    }
+    else if self.flat_count >= settings.flat_samples {
+      // a channel pinned to a rail shows no noise either
+      Some(rail.unwrap_or(Fault::Flat))
+    }
+    else if self.slew_score > settings.slew_events * SLEW_WINDOW {
+      Some(Fault::Slew)
+    }
+    else {
+      None
+    };
+    self.fault
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run(values: impl Iterator<Item=u16>, settings: &FaultSettings) -> Option<Fault> {
+    let mut health = ChannelHealth::default();
+    let settings = settings.to_fixed(&ScanSettings { rate_hz: 1000 });
+    values.filter_map(|v| health.check(v, &settings)).next()
+  }
+
+  #[test]
+  fn detects_faults() {
+    let settings = FaultSettings {
+      rail_ms: 100, flat_ms: 50, ..Default::default()
+    };
+    // healthy noise around rest, and a full press
+    let noise = (0..1000).map(|i| 2048 + (i * 7 % 5) as u16);
+    assert_eq!(run(noise, &settings), None);
+    let press = (0..1000).map(|i| 2048 - (i.min(500) * 3) as u16 + (i * 7 % 5) as u16);
+    assert_eq!(run(press, &settings), None);
+    assert_eq!(run((0..100).map(|_| 2048), &settings), Some(Fault::Flat));
+    assert_eq!(run((0..100).map(|i| (i % 3) as u16), &settings), Some(Fault::RailLow));
+    assert_eq!(run((0..100).map(|_| 4095), &settings), Some(Fault::RailHigh));
+    let floating = (0..100).map(|i| if i % 2 == 0 { 500 } else { 3500 });
+    assert_eq!(run(floating, &settings), Some(Fault::Slew));
+  }
+}
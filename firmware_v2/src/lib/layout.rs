@@ -7,11 +7,34 @@ use core::str;
 use core::fmt::Write;
 
 use crate::prelude::*;
+use crate::status::StatusConfig;
+use crate::board::ActuationOverride;
 
 #[derive(Debug,Serialize,Deserialize)]
 pub struct Keymap {
   pub layout: LayoutKind,
   pub layers: Vec<Vec<Behavior, MAX_KEYS>, MAX_LAYERS>,
+  // behaviors for keys pressed past their deep trigger, layered like `layers`
+  // (missing layers and keys are transparent)
+  #[serde(default)]
+  pub deep_layers: Vec<Vec<Behavior, MAX_KEYS>, MAX_LAYERS>,
+  // initial actuation overrides, also editable over serial
+  #[serde(default)]
+  pub actuation: Vec<ActuationOverride, MAX_OVERRIDES>,
+  // overrides the board status colors, e.g. to match layer colors to the keymap
+  #[serde(default)]
+  pub status: Option<StatusConfig>,
+  #[serde(default)]
+  pub timeouts: Timeouts,
+}
+
+// Time without key changes after which a mode is dropped, 0 never times out.
+// Nothing times out unless the keymap opts in.
+#[derive(Debug,Clone,Copy,Default,Serialize,Deserialize)]
+#[serde(default)]
+pub struct Timeouts {
+  pub caps_word_ms: u32,
+  pub one_shot_ms: u32,
 }
 
 #[derive(Debug,Clone,Copy)]
@@ -277,11 +300,24 @@ make_behavior_enum!(
   (RShift, "KC_RSFT"),
   (LGui, "KC_LGUI"),
   (RGui, "KC_RGUI"),
+  // one-shot modifiers
+  (OneShotLCtrl, "OS_LCTL"),
+  (OneShotRCtrl, "OS_RCTL"),
+  (OneShotLAlt, "OS_LALT"),
+  (OneShotRAlt, "OS_RALT"),
+  (OneShotLShift, "OS_LSFT"),
+  (OneShotRShift, "OS_RSFT"),
+  (OneShotLGui, "OS_LGUI"),
+  (OneShotRGui, "OS_RGUI"),
   // keyboard controls
   (BacklightToggle, "BL_TOGG"),
   (BacklightUp, "BL_UP"),
   (BacklightDown, "BL_DOWN"),
   (Reset, "QK_BOOT"),
+  (Calibrate, "QK_CAL"),
+  // locks
+  (CapsLock, "KC_CAPS"),
+  (CapsWord, "CW_TOGG"),
 );
 
 pub fn behavior_to_utf8(b: Behavior) -> Vec<u8, 64> {
@@ -353,6 +389,13 @@ pub fn behavior_to_utf8(b: Behavior) -> Vec<u8, 64> {
     LAlt | RAlt => write!(buf, "Alt"),
     LShift | RShift => write!(buf, "Sft"),
     LGui | RGui => write!(buf, "Cmd"),
+    OneShotLCtrl | OneShotRCtrl => write!(buf, "oCt"),
+    OneShotLAlt | OneShotRAlt => write!(buf, "oAl"),
+    OneShotLShift | OneShotRShift => write!(buf, "oSf"),
+    OneShotLGui | OneShotRGui => write!(buf, "oCm"),
+    CapsLock => write!(buf, "Cap"),
+    CapsWord => write!(buf, "CW"),
+    Calibrate => write!(buf, "Cal"),
     // TODO: other symbols
     _ => write!(buf, "<?>"),
   }.unwrap();
@@ -1,9 +1,5 @@
 #![cfg_attr(not(test), no_std)]
 
-use core::convert::Infallible;
-use ehal::blocking::delay::DelayUs;
-use ehal::digital::v2::{InputPin, OutputPin};
-
 pub mod layout;
 pub mod board;
 pub mod switch_matrix;
@@ -11,10 +7,23 @@ pub mod vkeyboard;
 pub mod usb;
 pub mod error;
 pub mod basic;
+pub mod status;
+pub mod console;
+pub mod calibration;
+pub mod settings;
+pub mod filter;
+pub mod health;
+pub mod profile;
+pub mod chatter;
+pub mod clock;
+pub mod event_queue;
+pub mod schedule;
 
 pub mod prelude {
   pub const BUS_WIDTH: usize = 4;
   pub const SEL_WIDTH: usize = 4;
+  // each switch on a register can cross its shallow and deep triggers at once
+  pub const MAX_REG_EVENTS: usize = 2 * BUS_WIDTH;
   
   pub const MAX_KEYS: usize = 128;
   pub const MAX_LAYERS: usize = 8;
@@ -31,15 +40,21 @@ pub mod prelude {
   pub type RegIndex = u8;
   pub type RegBitIndex = u8;
   pub type RegValue = [u16; BUS_WIDTH];
+  pub const ADC_MAX: u16 = 4096;
   pub type PinIndex = u8;
 
   pub const MAX_EVENTS: usize = 16;
+  pub const MAX_OVERRIDES: usize = 64;
 
   pub const USB_CLASS_HID: u8 = 3;
   pub const NKRO_MIN_KEY: u8 = 0x02;
   pub const NKRO_MAX_KEY: u8 = 0x81;
+  // the boot keyboard descriptor's Logical Maximum
+  pub const BOOT_MAX_KEY: u8 = 0x65;
   pub const MIN_MODIFIER: u8 = 0xe0;
   pub const MAX_MODIFIER: u8 = 0xe7;
+  pub const NKRO_LEN: usize = (NKRO_MAX_KEY - NKRO_MIN_KEY + 1) as usize;
+  pub const NUM_MODIFIERS: usize = (MAX_MODIFIER - MIN_MODIFIER + 1) as usize;
   
   pub trait TryIntoOutputPin {
     type Pin;
@@ -55,32 +70,12 @@ pub mod prelude {
   // re-export all error types
   pub use crate::error::*;
 }
-use prelude::*;
 
 pub mod bus {
   use crate::prelude::*;
+  // one reading of each bus channel, None where the conversion failed
+  pub type BusReading = [Option<u16>; BUS_WIDTH];
   pub trait AnalogBus {
-    fn read(&mut self) -> RegValue;
-  }
-}
-
-// TODO: need clock info
-pub fn tick<D: DelayUs<u32>, Q: OutputPin<Error=Infallible>, B: bus::AnalogBus>(
-  mut bus: B,
-  switches: &mut switch_matrix::SwitchMatrix<Q>,
-  vkbd: &mut vkeyboard::VKeyboard,
-  delay: &mut D,
-  write_fmt: impl Fn(core::fmt::Arguments) -> ())
-  -> Result<(bool, B), Error>
-{
-  let mut updated = false;
-  for i in 0..switches.num_regs() {
-    let key_events = switches.subtick(i as RegIndex, &mut bus, delay, &write_fmt)?;
-    let now_updated = vkbd.update(key_events, &write_fmt)?;
-    updated = updated || now_updated;
-    if vkbd.reset {
-      break;
-    }
+    fn read(&mut self) -> BusReading;
   }
-  return Ok((updated, bus));
 }
@@ -0,0 +1,130 @@
+use serde::{Serialize, Deserialize};
+use heapless::{String, Vec};
+
+use crate::board::{Polarity, SwitchSettings};
+
+pub const MAX_PROFILES: usize = 8;
+pub const MAX_PROFILE_POINTS: usize = 16;
+pub const MAX_PROFILE_NAME: usize = 32;
+
+// Maps a switch's reading to key travel. Field strength falls off steeply with
+// magnet distance, so most of the change in reading happens near bottom-out.
+// The shipped profiles (profiles/switches.json) are dipole fits to each
+// switch's nominal travel, measure a switch to refine its curve.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwitchProfile {
+  pub name: String<MAX_PROFILE_NAME>,
+  pub travel_mm: f32,
+  // (fraction of the calibrated range from rest to bottom-out, travel in mm),
+  // both increasing, from (0, 0) to (1, travel_mm)
+  pub curve: Vec<(f32, f32), MAX_PROFILE_POINTS>,
+}
+
+// piecewise linear, clamped to the ends of the curve
+fn interpolate(points: impl Iterator<Item=(f32, f32)>, x: f32) -> f32 {
+  let mut prev: Option<(f32, f32)> = None;
+  for (x1, y1) in points {
+    match prev {
+      None if x <= x1 => return y1,
+      Some((x0, y0)) if x <= x1 => {
+        return if x1 > x0 { y0 + (y1 - y0) * (x - x0) / (x1 - x0) } else { y1 };
+      }
+      _ => prev = Some((x1, y1)),
+    }
+  }
+  prev.map_or(0.0, |(_, y)| y)
+}
+
+impl SwitchProfile {
+  pub fn is_valid(&self) -> bool {
+    self.curve.len() >= 2
+      && self.curve.windows(2).all(|w| w[1].0 > w[0].0 && w[1].1 > w[0].1)
+  }
+
+  pub fn to_mm(&self, fraction: f32) -> f32 {
+    interpolate(self.curve.iter().copied(), fraction)
+  }
+
+  pub fn to_fraction(&self, mm: f32) -> f32 {
+    interpolate(self.curve.iter().map(|&(f, mm)| (mm, f)), mm)
+  }
+
+  // Travel of a normalized reading, for a switch with a calibrated range.
+  pub fn reading_to_mm(&self, settings: &SwitchSettings, value: f32) -> Option<f32> {
+    let range = settings.range?;
+    let travel = range.bottom - range.rest;
+    if travel == 0.0 {
+      return None;
+    }
+    Some(self.to_mm((value - range.rest) / travel))
+  }
+
+  // Replace the thresholds of a switch by its actuation points in mm, if it
+  // has them and a calibrated range. Settings are unchanged when the result
+  // would be invalid.
+  pub fn apply(&self, settings: SwitchSettings) -> SwitchSettings {
+    let (range, travel) = match (settings.range, settings.travel) {
+      (Some(range), Some(travel)) => (range, travel),
+      _ => return settings,
+    };
+    let to_reading = |mm: f32| range.rest + (range.bottom - range.rest) * self.to_fraction(mm);
+    let applied = SwitchSettings {
+      trig_down: to_reading(travel.down_mm),
+      trig_up: to_reading(travel.up_mm),
+      ..settings
+    };
+    // the range decides the direction, not the configured polarity
+    let polarity_matches = match applied.polarity {
+      Polarity::S => range.bottom < range.rest,
+      Polarity::N => range.bottom > range.rest,
+    };
+    if polarity_matches && applied.is_valid() { applied } else { settings }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::board::{SwitchRange, TravelTrigger};
+
+  fn profiles() -> Vec<SwitchProfile, MAX_PROFILES> {
+    let (profiles, _): (Vec<SwitchProfile, MAX_PROFILES>, usize) =
+      serde_json::from_slice(include_bytes!("../../profiles/switches.json")).unwrap();
+    profiles
+  }
+
+  #[test]
+  fn shipped_profiles_are_valid() {
+    let profiles = profiles();
+    assert!(profiles.len() >= 2);
+    for profile in profiles.iter() {
+      assert!(profile.is_valid(), "{}", profile.name);
+      assert_eq!(profile.curve.last().unwrap().1, profile.travel_mm);
+      for mm in [0.5, 1.2, 2.0, 3.0] {
+        assert!((profile.to_mm(profile.to_fraction(mm)) - mm).abs() < 1e-4);
+      }
+    }
+  }
+
+  #[test]
+  fn mm_thresholds() {
+    let linear = profiles().into_iter().find(|p| p.name == "linear-4mm").unwrap();
+    assert_eq!(linear.to_fraction(1.0), 0.25);
+    assert_eq!(linear.to_mm(2.0), 4.0);
+    let settings = SwitchSettings {
+      polarity: Polarity::S,
+      range: Some(SwitchRange { rest: 0.5, bottom: 0.1 }),
+      travel: Some(TravelTrigger { down_mm: 2.0, up_mm: 1.0 }),
+      ..SwitchSettings::default()
+    };
+    let applied = linear.apply(settings);
+    assert!((applied.trig_down - 0.3).abs() < 1e-6 && (applied.trig_up - 0.4).abs() < 1e-6);
+    assert!((linear.reading_to_mm(&applied, 0.3).unwrap() - 2.0).abs() < 1e-5);
+    // up deeper than down is rejected
+    let backwards = SwitchSettings {
+      travel: Some(TravelTrigger { down_mm: 1.0, up_mm: 2.0 }),
+      ..settings
+    };
+    assert_eq!(linear.apply(backwards).trig_down, settings.trig_down);
+  }
+}
@@ -0,0 +1,90 @@
+use serde::{Serialize, Deserialize};
+
+use crate::clock::Instant;
+use crate::prelude::*;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanSettings {
+  // full matrix scans per second, on a fixed schedule
+  pub rate_hz: u32,
+}
+
+impl Default for ScanSettings {
+  fn default() -> Self {
+    // matches the USB poll interval
+    Self { rate_hz: 1000 }
+  }
+}
+
+impl ScanSettings {
+  // Number of scans in a span of time, at least one. Settings that count
+  // samples are given as times, so they hold at any scan rate.
+  pub fn samples(&self, ms: u32) -> u32 {
+    (ms as u64 * self.rate_hz as u64 / 1000).clamp(1, u32::MAX as u64) as u32
+  }
+}
+
+// Deadlines for scans at a fixed rate. Deadlines advance by the period, not
+// from when the last scan finished, so the rate does not depend on how long
+// scans or other work take.
+pub struct ScanSchedule {
+  period_us: u64,
+  deadline: Instant,
+}
+
+impl ScanSchedule {
+  pub fn new(settings: ScanSettings, now: Instant) -> Result<Self, Error> {
+    if settings.rate_hz == 0 || settings.rate_hz > 1_000_000 {
+      return Err(Error::InvalidSettings);
+    }
+    let period_us = 1_000_000 / settings.rate_hz as u64;
+    Ok(Self { period_us, deadline: now + period_us })
+  }
+
+  pub fn period_us(&self) -> u64 {
+    self.period_us
+  }
+
+  // when the next scan is due
+  pub fn deadline(&self) -> Instant {
+    self.deadline
+  }
+
+  // Records that a scan started at `now`, returns how late it started and how
+  // many deadlines passed without a scan. Missed scans are skipped rather
+  // than run back to back.
+  pub fn start(&mut self, now: Instant) -> (u32, u32) {
+    let late_us = now.saturating_sub(self.deadline);
+    let missed = late_us / self.period_us;
+    self.deadline += (missed + 1) * self.period_us;
+    (late_us as u32, missed as u32)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_fixed_rate() {
+    let mut schedule = ScanSchedule::new(ScanSettings { rate_hz: 1000 }, 5_000).unwrap();
+    assert_eq!(schedule.deadline(), 6_000);
+    assert_eq!(schedule.start(6_003), (3, 0));
+    // lateness does not push back later deadlines
+    assert_eq!(schedule.deadline(), 7_000);
+    assert_eq!(schedule.start(7_000), (0, 0));
+    // a long stall skips the scans it overran
+    assert_eq!(schedule.start(10_500), (2_500, 2));
+    assert_eq!(schedule.deadline(), 11_000);
+    assert!(ScanSchedule::new(ScanSettings { rate_hz: 0 }, 0).is_err());
+  }
+
+  #[test]
+  fn converts_times_to_samples() {
+    assert_eq!(ScanSettings { rate_hz: 1000 }.samples(30_000), 30_000);
+    assert_eq!(ScanSettings { rate_hz: 5000 }.samples(2_000), 10_000);
+    // never rounds down to no samples at all
+    assert_eq!(ScanSettings { rate_hz: 100 }.samples(1), 1);
+  }
+}
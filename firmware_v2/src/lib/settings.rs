@@ -0,0 +1,375 @@
+use serde::{Serialize, Deserialize};
+use ehal::digital::v2::OutputPin;
+use heapless::Vec;
+
+use crate::board::{ActuationOverride, SwitchSettings};
+use crate::switch_matrix::SwitchMatrix;
+use crate::vkeyboard::VKeyboard;
+use crate::prelude::*;
+
+pub const FLASH_SECTOR_SIZE: usize = 4096;
+pub const FLASH_PAGE_SIZE: usize = 256;
+
+// bump when Settings changes incompatibly, older records are then ignored
+pub const SETTINGS_VERSION: u16 = 1;
+// a full calibration with every option set on every key, two records must
+// fit in the store
+pub const MAX_SETTINGS_LEN: usize = 24 * 1024;
+
+const RECORD_MAGIC: u32 = u32::from_le_bytes(*b"UCST");
+const HEADER_LEN: usize = 16;
+const MAX_RECORD_LEN: usize = HEADER_LEN + MAX_SETTINGS_LEN;
+// scratch space for a whole record padded to a page, too big for the stack
+pub const RECORD_BUF_LEN: usize = MAX_RECORD_LEN + FLASH_PAGE_SIZE;
+
+// A region of NOR flash reserved for settings, addressed from its start.
+// Erased bytes read as 0xff, programming can only clear bits.
+pub trait Flash {
+  // size of the region, a multiple of FLASH_SECTOR_SIZE
+  fn capacity(&self) -> usize;
+  fn read(&self, offset: usize, buf: &mut [u8]);
+  // erase the sector starting at `offset`
+  fn erase(&mut self, offset: usize);
+  // `offset` and the length of `data` are multiples of FLASH_PAGE_SIZE
+  fn program(&mut self, offset: usize, data: &[u8]);
+}
+
+// User state that survives power cycles, applied over the compiled-in board
+// and keymap
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+  pub default_layer: LayerIndex,
+  // switch calibration by register, empty to keep the board calibration
+  pub calibration: Vec<[SwitchSettings; BUS_WIDTH], MAX_REGS>,
+  // replaces the keymap actuation overrides
+  pub actuation: Vec<ActuationOverride, MAX_OVERRIDES>,
+}
+
+impl Settings {
+  pub fn capture<Q: OutputPin>(switches: &SwitchMatrix<Q>, vkbd: &VKeyboard) -> Self {
+    Self {
+      default_layer: vkbd.default_layer(),
+      calibration: Vec::from_slice(switches.calibration()).unwrap(),
+      actuation: Vec::from_slice(switches.overrides()).unwrap(),
+    }
+  }
+
+  // All or nothing, everything is checked before anything is applied.
+  pub fn apply<Q: OutputPin>(
+    &self, switches: &mut SwitchMatrix<Q>, vkbd: &mut VKeyboard) -> Result<(), Error>
+  {
+    vkbd.check_default_layer(self.default_layer)?;
+    switches.check_settings(&self.calibration[..], &self.actuation[..])?;
+    vkbd.set_default_layer(self.default_layer)?;
+    if !self.calibration.is_empty() {
+      switches.set_calibration(&self.calibration[..])?;
+    }
+    switches.set_overrides(&self.actuation[..])
+  }
+}
+
+// CRC-32 (IEEE), continued from a previous `crc` or started from 0
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+  let mut crc = !crc;
+  for &byte in data {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+      let mask = (crc & 1).wrapping_neg();
+      crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+    }
+  }
+  !crc
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Header {
+  version: u16,
+  len: u16,
+  seq: u32,
+  crc: u32,
+}
+
+impl Header {
+  fn to_bytes(self) -> [u8; HEADER_LEN] {
+    let mut bytes = [0; HEADER_LEN];
+    bytes[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+    bytes[6..8].copy_from_slice(&self.len.to_le_bytes());
+    bytes[8..12].copy_from_slice(&self.seq.to_le_bytes());
+    bytes[12..16].copy_from_slice(&self.crc.to_le_bytes());
+    bytes
+  }
+
+  fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Option<Self> {
+    let word = |i: usize| u32::from_le_bytes(bytes[i..i+4].try_into().unwrap());
+    let half = |i: usize| u16::from_le_bytes(bytes[i..i+2].try_into().unwrap());
+    if word(0) != RECORD_MAGIC {
+      return None;
+    }
+    Some(Self { version: half(4), len: half(6), seq: word(8), crc: word(12) })
+  }
+
+  // covers everything but the crc itself
+  fn crc(&self, payload_crc: u32) -> u32 {
+    crc32(crc32(0, &self.to_bytes()[..12]), &payload_crc.to_le_bytes())
+  }
+}
+
+fn round_up(n: usize, align: usize) -> usize {
+  n.div_ceil(align) * align
+}
+
+// Settings records are appended one after another through the region and
+// wrap around at its end, so every sector is erased equally often. Each record
+// starts on a page with a header holding the format version, payload length,
+// a sequence number and a CRC. The valid record with the highest sequence
+// number is current, so a record torn by power loss is ignored in favor of the
+// one before it.
+pub struct SettingsStore<F: Flash> {
+  flash: F,
+  // offset and header of the current record
+  current: Option<(usize, Header)>,
+  // next free offset
+  next: usize,
+  buf: &'static mut [u8; RECORD_BUF_LEN],
+}
+
+impl<F: Flash> SettingsStore<F> {
+  pub fn new(flash: F, buf: &'static mut [u8; RECORD_BUF_LEN]) -> Self {
+    assert!(flash.capacity() >= 2 * round_up(MAX_RECORD_LEN, FLASH_SECTOR_SIZE)
+            + FLASH_SECTOR_SIZE);
+    let mut store = Self { flash, current: None, next: 0, buf };
+    store.scan();
+    store
+  }
+
+  fn payload_crc(&self, offset: usize, len: usize) -> u32 {
+    let mut crc = 0;
+    let mut buf = [0; FLASH_PAGE_SIZE];
+    let mut pos = 0;
+    while pos < len {
+      let n = (len - pos).min(buf.len());
+      self.flash.read(offset + HEADER_LEN + pos, &mut buf[..n]);
+      crc = crc32(crc, &buf[..n]);
+      pos += n;
+    }
+    crc
+  }
+
+  fn read_record(&self, offset: usize) -> Option<Header> {
+    let mut bytes = [0; HEADER_LEN];
+    self.flash.read(offset, &mut bytes);
+    let header = Header::from_bytes(&bytes)?;
+    let len = header.len as usize;
+    if len > MAX_SETTINGS_LEN || offset + HEADER_LEN + len > self.flash.capacity() {
+      return None;
+    }
+    if header.crc(self.payload_crc(offset, len)) != header.crc {
+      return None;
+    }
+    Some(header)
+  }
+
+  fn scan(&mut self) {
+    let mut offset = 0;
+    while offset < self.flash.capacity() {
+      match self.read_record(offset) {
+        Some(header) => {
+          if self.current.is_none_or(|(_, cur)| header.seq > cur.seq) {
+            self.current = Some((offset, header));
+          }
+          offset += round_up(HEADER_LEN + header.len as usize, FLASH_PAGE_SIZE);
+        }
+        None => offset += FLASH_PAGE_SIZE,
+      }
+    }
+    if let Some((offset, header)) = self.current {
+      self.next = offset + round_up(HEADER_LEN + header.len as usize, FLASH_PAGE_SIZE);
+    }
+  }
+
+  // the current record, unless it is missing or from another version
+  pub fn load(&mut self) -> Option<Settings> {
+    let (offset, header) = self.current?;
+    if header.version != SETTINGS_VERSION {
+      return None;
+    }
+    let payload = &mut self.buf[..header.len as usize];
+    self.flash.read(offset + HEADER_LEN, payload);
+    serde_json::from_slice(payload).ok().map(|(settings, _)| settings)
+  }
+
+  fn is_blank(&self, start: usize, end: usize) -> bool {
+    let mut buf = [0; FLASH_PAGE_SIZE];
+    let mut pos = start;
+    while pos < end {
+      let n = (end - pos).min(buf.len());
+      self.flash.read(pos, &mut buf[..n]);
+      if buf[..n].iter().any(|&b| b != 0xff) {
+        return false;
+      }
+      pos += n;
+    }
+    true
+  }
+
+  // Sectors are erased just ahead of the records written into them, so the
+  // rest of the sector after the current record is normally still blank.
+  // After a torn write it is not, and the record moves on to the next sector.
+  fn place(&self, len: usize) -> usize {
+    let capacity = self.flash.capacity();
+    let mut start = self.next;
+    if start + len > capacity {
+      start = 0;
+    }
+    let sector_end = round_up(start + 1, FLASH_SECTOR_SIZE).min(start + len);
+    if !start.is_multiple_of(FLASH_SECTOR_SIZE) && !self.is_blank(start, sector_end) {
+      start = round_up(start, FLASH_SECTOR_SIZE);
+      if start + len > capacity {
+        start = 0;
+      }
+    }
+    start
+  }
+
+  pub fn save(&mut self, settings: &Settings) -> Result<(), Error> {
+    let buf = &mut *self.buf;
+    let len = serde_json::to_slice(settings, &mut buf[HEADER_LEN..MAX_RECORD_LEN])
+      .map_err(|_| Error::SerializeError)?;
+    let mut header = Header {
+      version: SETTINGS_VERSION,
+      len: len as u16,
+      seq: self.current.map_or(0, |(_, cur)| cur.seq.wrapping_add(1)),
+      crc: 0,
+    };
+    header.crc = header.crc(crc32(0, &buf[HEADER_LEN..HEADER_LEN + len]));
+    buf[..HEADER_LEN].copy_from_slice(&header.to_bytes());
+
+    let record_len = round_up(HEADER_LEN + len, FLASH_PAGE_SIZE);
+    // pad with erased bytes, leaving the rest of the page programmable
+    buf[HEADER_LEN + len..record_len].fill(0xff);
+    let start = self.place(record_len);
+    let first_sector = round_up(start, FLASH_SECTOR_SIZE);
+    for sector in (first_sector..start + record_len).step_by(FLASH_SECTOR_SIZE) {
+      self.flash.erase(sector);
+    }
+    self.flash.program(start, &self.buf[..record_len]);
+
+    match self.read_record(start) {
+      Some(written) if written.seq == header.seq => {
+        self.current = Some((start, header));
+        self.next = start + record_len;
+        Ok(())
+      }
+      _ => Err(Error::FlashError),
+    }
+  }
+
+  // Erase every record, so the compiled-in defaults apply from the next boot.
+  pub fn clear(&mut self) {
+    for sector in (0..self.flash.capacity()).step_by(FLASH_SECTOR_SIZE) {
+      self.flash.erase(sector);
+    }
+    self.current = None;
+    self.next = 0;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::board::Polarity;
+
+  struct MemFlash {
+    data: std::vec::Vec<u8>,
+    erases: std::vec::Vec<u32>,
+  }
+
+  impl MemFlash {
+    fn new(sectors: usize) -> Self {
+      Self {
+        data: std::vec![0xff; sectors * FLASH_SECTOR_SIZE],
+        erases: std::vec![0; sectors],
+      }
+    }
+  }
+
+  impl Flash for &mut MemFlash {
+    fn capacity(&self) -> usize {
+      self.data.len()
+    }
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+      buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+    }
+    fn erase(&mut self, offset: usize) {
+      assert_eq!(offset % FLASH_SECTOR_SIZE, 0);
+      self.data[offset..offset + FLASH_SECTOR_SIZE].fill(0xff);
+      self.erases[offset / FLASH_SECTOR_SIZE] += 1;
+    }
+    fn program(&mut self, offset: usize, data: &[u8]) {
+      assert_eq!(offset % FLASH_PAGE_SIZE, 0);
+      assert_eq!(data.len() % FLASH_PAGE_SIZE, 0);
+      for (dst, src) in self.data[offset..].iter_mut().zip(data) {
+        *dst &= *src;
+      }
+    }
+  }
+
+  fn open(flash: &mut MemFlash) -> SettingsStore<&mut MemFlash> {
+    let buf = std::boxed::Box::leak(std::boxed::Box::new([0; RECORD_BUF_LEN]));
+    SettingsStore::new(flash, buf)
+  }
+
+  fn settings(layer: LayerIndex) -> Settings {
+    let mut settings = Settings { default_layer: layer, ..Default::default() };
+    let switch = SwitchSettings { polarity: Polarity::N, ..Default::default() };
+    settings.calibration.push([switch; BUS_WIDTH]).unwrap();
+    settings.actuation.push(ActuationOverride {
+      key: 3, layer: Some(1), trig_down: 0.25, trig_up: 0.2,
+    }).unwrap();
+    settings
+  }
+
+  #[test]
+  fn save_and_reload() {
+    let mut flash = MemFlash::new(16);
+    assert!(open(&mut flash).load().is_none());
+    open(&mut flash).save(&settings(2)).unwrap();
+    let loaded = open(&mut flash).load().unwrap();
+    assert_eq!(loaded.default_layer, 2);
+    assert!(matches!(loaded.calibration[0][3].polarity, Polarity::N));
+    assert_eq!(loaded.actuation[0], settings(2).actuation[0]);
+  }
+
+  #[test]
+  fn wear_leveled_and_latest_wins() {
+    let mut flash = MemFlash::new(16);
+    let mut store = open(&mut flash);
+    for i in 0..200 {
+      store.save(&settings((i % 8) as LayerIndex)).unwrap();
+    }
+    assert_eq!(open(&mut flash).load().unwrap().default_layer, 199 % 8);
+    let (min, max) = (flash.erases.iter().min().unwrap(), flash.erases.iter().max().unwrap());
+    assert!(*min > 0 && max - min <= 1);
+  }
+
+  #[test]
+  fn corrupt_record_falls_back() {
+    let mut flash = MemFlash::new(16);
+    let mut store = open(&mut flash);
+    store.save(&settings(1)).unwrap();
+    store.save(&settings(2)).unwrap();
+    let (offset, _) = store.current.unwrap();
+    // flip a payload bit of the latest record
+    flash.data[offset + HEADER_LEN + 4] ^= 0x04;
+    let mut store = open(&mut flash);
+    assert_eq!(store.load().unwrap().default_layer, 1);
+    // the torn sector is skipped by the next write
+    store.save(&settings(3)).unwrap();
+    assert_eq!(open(&mut flash).load().unwrap().default_layer, 3);
+    // nothing valid left, so defaults apply
+    open(&mut flash).clear();
+    assert!(open(&mut flash).load().is_none());
+  }
+}
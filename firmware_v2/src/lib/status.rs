@@ -0,0 +1,176 @@
+use serde::{Serialize, Deserialize};
+use heapless::Vec;
+
+use crate::prelude::*;
+
+pub type Rgb = (u8, u8, u8);
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum Blink {
+  #[default]
+  Solid,
+  // 1 Hz on/off
+  Slow,
+  // 5 Hz on/off
+  Fast,
+  // 2 s fade in and out
+  Pulse,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusColor {
+  pub color: Rgb,
+  #[serde(default)]
+  pub blink: Blink,
+}
+
+impl StatusColor {
+  const fn new(color: Rgb, blink: Blink) -> Self {
+    Self { color, blink }
+  }
+
+  // color to display `now_ms` into the blink pattern
+  pub fn at(&self, now_ms: u32) -> Rgb {
+    let (r, g, b) = self.color;
+    let scale = |c: u8, num: u32, den: u32| (c as u32 * num / den) as u8;
+    match self.blink {
+      Blink::Solid => self.color,
+      Blink::Slow => if now_ms % 1000 < 500 { self.color } else { (0, 0, 0) },
+      Blink::Fast => if now_ms % 200 < 100 { self.color } else { (0, 0, 0) },
+      Blink::Pulse => {
+        let t = now_ms % 2000;
+        let level = if t < 1000 { t } else { 2000 - t };
+        (scale(r, level, 1000), scale(g, level, 1000), scale(b, level, 1000))
+      }
+    }
+  }
+}
+
+// Neopixel colors, from the board or keymap JSON under "status". Missing
+// fields keep their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusConfig {
+  // indexed by highest active layer, the last entry covers any higher layers
+  pub layers: Vec<StatusColor, MAX_LAYERS>,
+  pub caps_lock: StatusColor,
+  pub caps_word: StatusColor,
+  pub one_shot: StatusColor,
+  pub calibrating: StatusColor,
+  // a key was disabled by a sensor fault
+  pub fault: StatusColor,
+  pub bootloader: StatusColor,
+  pub usb_unconfigured: StatusColor,
+}
+
+impl Default for StatusConfig {
+  fn default() -> Self {
+    Self {
+      layers: Vec::from_slice(&[
+        StatusColor::new((50, 0, 50), Blink::Solid),
+        StatusColor::new((0, 0, 60), Blink::Solid),
+        StatusColor::new((0, 50, 0), Blink::Solid),
+        StatusColor::new((50, 30, 0), Blink::Solid),
+      ]).unwrap(),
+      caps_lock: StatusColor::new((0, 50, 50), Blink::Solid),
+      caps_word: StatusColor::new((0, 50, 50), Blink::Slow),
+      one_shot: StatusColor::new((50, 50, 50), Blink::Fast),
+      calibrating: StatusColor::new((0, 0, 60), Blink::Fast),
+      fault: StatusColor::new((75, 0, 0), Blink::Slow),
+      bootloader: StatusColor::new((50, 50, 0), Blink::Solid),
+      usb_unconfigured: StatusColor::new((50, 20, 0), Blink::Pulse),
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Status {
+  pub layer: LayerIndex,
+  pub caps_lock: bool,
+  pub caps_word: bool,
+  pub one_shot: bool,
+  pub calibrating: bool,
+  pub fault: bool,
+  pub bootloader: bool,
+  pub usb_configured: bool,
+}
+
+impl StatusConfig {
+  // Only the most urgent state is shown: errors first, then transient
+  // modifier state, then locks, falling back to the active layer.
+  pub fn select(&self, status: &Status) -> StatusColor {
+    if status.bootloader {
+      self.bootloader
+    }
+    else if !status.usb_configured {
+      self.usb_unconfigured
+    }
+    else if status.fault {
+      self.fault
+    }
+    else if status.calibrating {
+      self.calibrating
+    }
+    else if status.one_shot {
+      self.one_shot
+    }
+    else if status.caps_word {
+      self.caps_word
+    }
+    else if status.caps_lock {
+      self.caps_lock
+    }
+    else {
+      self.layers.get(status.layer as usize)
+        .or(self.layers.last())
+        .copied()
+        .unwrap_or(StatusColor::new((0, 0, 0), Blink::Solid))
+    }
+  }
+
+  pub fn color(&self, status: &Status, now_ms: u32) -> Rgb {
+    self.select(status).at(now_ms)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn status_priority() {
+    let config = StatusConfig::default();
+    let mut status = Status { layer: 6, usb_configured: true, ..Default::default() };
+    assert_eq!(config.select(&status), config.layers[3]);
+    status.caps_lock = true;
+    assert_eq!(config.select(&status), config.caps_lock);
+    status.one_shot = true;
+    assert_eq!(config.select(&status), config.one_shot);
+    status.usb_configured = false;
+    assert_eq!(config.select(&status), config.usb_unconfigured);
+  }
+
+  #[test]
+  fn blink_patterns() {
+    let slow = StatusColor::new((10, 20, 30), Blink::Slow);
+    assert_eq!(slow.at(1200), (10, 20, 30));
+    assert_eq!(slow.at(1700), (0, 0, 0));
+    let pulse = StatusColor::new((100, 0, 0), Blink::Pulse);
+    assert_eq!(pulse.at(0), (0, 0, 0));
+    assert_eq!(pulse.at(500), (50, 0, 0));
+    assert_eq!(pulse.at(1000), (100, 0, 0));
+    assert_eq!(pulse.at(1500), (50, 0, 0));
+  }
+
+  #[test]
+  fn partial_config() -> Result<(), String> {
+    let (config, _): (StatusConfig, usize) = serde_json::from_slice(
+      br#"{"caps_word": {"color": [1, 2, 3]}, "layers": [{"color": [4, 5, 6], "blink": "Fast"}]}"#)
+      .map_err(|e| format!("{}", e))?;
+    assert_eq!(config.caps_word, StatusColor::new((1, 2, 3), Blink::Solid));
+    assert_eq!(config.layers.len(), 1);
+    assert_eq!(config.layers[0].blink, Blink::Fast);
+    assert_eq!(config.caps_lock, StatusConfig::default().caps_lock);
+    Ok(())
+  }
+}
@@ -4,99 +4,435 @@ use ehal::blocking::delay::DelayUs;
 use core::convert::Infallible;
 
 use crate::bus::AnalogBus;
-use crate::board::{RegMap, Polarity, SwitchSettings};
-use crate::vkeyboard::KeyEvent;
+use crate::board::{
+  RegMap, Polarity, SwitchSettings, FixedSwitchSettings, ActuationOverride,
+  DriftSettings, FixedDriftSettings, Debounce};
+use crate::calibration::{Calibrator, CalibrationSettings};
+use crate::filter::{FilterSettings, FixedFilterSettings, RegFilter, NoiseMeter};
+use crate::health::{ChannelHealth, Fault, FaultSettings, FixedFaultSettings, SELF_TEST_SAMPLES};
+use crate::profile::SwitchProfile;
+use crate::chatter::{ChatterDetector, ChatterSettings};
+use crate::clock::{Clock, to_ms};
+use crate::vkeyboard::{KeyEvent, KeyChange};
+use crate::event_queue::EventQueue;
+use crate::schedule::ScanSettings;
 use crate::prelude::*;
 
 #[derive(Debug)]
 struct SwitchState {
   is_down: bool,
+  // past the deep trigger, only while is_down
+  is_deep: bool,
+  // for rapid trigger, deepest value while down and highest value while up
+  extreme: i32,
+  // consecutive samples past the next threshold
+  pending: u8,
   settings: SwitchSettings,
+  // the settings in ADC counts, used while scanning
+  fixed: FixedSwitchSettings,
 }
 
 impl SwitchState {
   fn new(settings: SwitchSettings) -> Self {
     Self {
       is_down: false,
+      is_deep: false,
+      extreme: 0,
+      pending: 0,
       settings,
+      fixed: settings.to_fixed(),
     }
   }
+
+  fn set_settings(&mut self, settings: SwitchSettings) {
+    self.settings = settings;
+    self.fixed = settings.to_fixed();
+  }
 }
 
-#[derive(Debug, Copy, Clone)]
+// fractional bits of the baseline average
+const BASELINE_BITS: u32 = 16;
+
+// Slowly adapting resting value of a switch, tracked while it is up
+#[derive(Debug, Copy, Clone, Default)]
+struct Baseline {
+  // resting value at the first sample, unset before it
+  reference: Option<i32>,
+  // in 1/2^BASELINE_BITS counts
+  value: i32,
+}
+
+impl Baseline {
+  // how far the resting value has moved since the thresholds were set, in
+  // counts
+  fn drift(&self) -> i32 {
+    match self.reference {
+      Some(reference) => {
+        let half = 1 << (BASELINE_BITS - 1);
+        ((self.value + half) >> BASELINE_BITS) - reference
+      }
+      None => 0,
+    }
+  }
+
+  fn track(&mut self, value: i32, settings: &FixedDriftSettings) {
+    let reference = match self.reference {
+      Some(reference) => reference,
+      None => {
+        self.reference = Some(value);
+        self.value = value << BASELINE_BITS;
+        return;
+      }
+    };
+    let diff = (value << BASELINE_BITS) - self.value;
+    if diff.abs() > settings.band << BASELINE_BITS {
+      return;
+    }
+    // rate is a fraction of 2^32, rounded to nearest so the average is unbiased
+    self.value += ((diff as i64 * settings.rate as i64 + (1 << 31)) >> 32) as i32;
+    self.value = self.value.clamp(
+      (reference - settings.max_drift) << BASELINE_BITS,
+      (reference + settings.max_drift) << BASELINE_BITS);
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum RegEvent {
-  None, SwitchDown, SwitchUp,
+  None, SwitchDown, SwitchUp, DeepDown, DeepUp,
 }
 
 struct RegState {
   is_enabled: [bool; BUS_WIDTH],
   state: [SwitchState; BUS_WIDTH],
+  baseline: [Baseline; BUS_WIDTH],
 }
 
-const ADC_MAX: u16 = 4096;
-
 impl RegState {
   fn new(calib: [SwitchSettings; BUS_WIDTH]) -> Self {
     Self {
       is_enabled: [false; BUS_WIDTH],
       state: calib.into_iter().map(SwitchState::new)
-        .collect::<Vec<SwitchState, BUS_WIDTH>>().into_array().unwrap()
+        .collect::<Vec<SwitchState, BUS_WIDTH>>().into_array().unwrap(),
+      baseline: [Baseline::default(); BUS_WIDTH],
     }
   }
-  fn update(&mut self, new_values: RegValue) -> [RegEvent; BUS_WIDTH] {
-    let mut events = [RegEvent::None; BUS_WIDTH];
+
+  // after recalibration, thresholds match the current resting values
+  fn reset_baselines(&mut self) {
+    self.baseline = [Baseline::default(); BUS_WIDTH];
+  }
+
+  // Events as (bit, event), in the order they happened. Runs for every
+  // register on every scan, so only integer arithmetic here.
+  fn update(
+    &mut self, new_values: RegValue, drift: &FixedDriftSettings)
+    -> Vec<(usize, RegEvent), MAX_REG_EVENTS>
+  {
+    let mut events = Vec::<(usize, RegEvent), MAX_REG_EVENTS>::new();
     for i in 0..BUS_WIDTH {
       if !self.is_enabled[i] {
         continue;
       }
       // polarity determines the direction of the triggers
       let state = &mut self.state[i];
-      let settings = state.settings;
-      let value_norm = new_values[i] as f32 / ADC_MAX as f32;
+      let settings = state.fixed;
+      let raw = new_values[i] as i32;
+      // thresholds follow the drift of the resting value
+      let value = raw - self.baseline[i].drift();
+      let mut event = RegEvent::None;
       // FORNOW:
       // if state.is_down {
       //   state.is_down = false;
-      //   events[i] = RegEvent::SwitchUp;
+      //   event = RegEvent::SwitchUp;
       // }
       // else {
       //   state.is_down = true;
-      //   events[i] = RegEvent::SwitchDown;
+      //   event = RegEvent::SwitchDown;
       // }
-      match settings.polarity {
-        Polarity::S => {
-          // we use hysteresis to debounce
-          assert!(settings.trig_up > settings.trig_down);
-          if state.is_down && value_norm > settings.trig_up {
-            state.is_down = false;
-            events[i] = RegEvent::SwitchUp;
-          }
-          else if !state.is_down && value_norm < settings.trig_down {
-            state.is_down = true;
-            events[i] = RegEvent::SwitchDown;
-          }
+      let crossed = match (settings.polarity, settings.rapid_trigger) {
+        (Polarity::S, Some(rt)) => if state.is_down {
+          state.extreme = state.extreme.min(value);
+          value > settings.trig_up || value > state.extreme + rt.release
         }
-        Polarity::N => {
-          // we use hysteresis to debounce
-          assert!(settings.trig_up < settings.trig_down);
-          if state.is_down && value_norm < settings.trig_up {
-            state.is_down = false;
-            events[i] = RegEvent::SwitchUp;
-          }
-          else if !state.is_down && value_norm > settings.trig_down {
-            state.is_down = true;
-            events[i] = RegEvent::SwitchDown;
-          }
+        else {
+          state.extreme = state.extreme.max(value);
+          // once past trig_up the key is fully released and must reach trig_down
+          let rapid = state.extreme < settings.trig_up && value < state.extreme - rt.press;
+          value < settings.trig_down || rapid
+        },
+        (Polarity::N, Some(rt)) => if state.is_down {
+          state.extreme = state.extreme.max(value);
+          value < settings.trig_up || value < state.extreme - rt.release
         }
+        else {
+          state.extreme = state.extreme.min(value);
+          // once past trig_up the key is fully released and must reach trig_down
+          let rapid = state.extreme > settings.trig_up && value > state.extreme + rt.press;
+          value > settings.trig_down || rapid
+        },
+        // we use hysteresis to debounce
+        (Polarity::S, None) => if state.is_down {
+          value > settings.trig_up
+        }
+        else {
+          value < settings.trig_down
+        },
+        (Polarity::N, None) => if state.is_down {
+          value < settings.trig_up
+        }
+        else {
+          value > settings.trig_down
+        },
+      };
+      // and confirm the change over consecutive samples, if configured
+      if crossed {
+        state.pending += 1;
+        let required = if state.is_down { settings.release_samples } else { settings.press_samples };
+        if state.pending >= required {
+          state.pending = 0;
+          state.is_down = !state.is_down;
+          state.extreme = value;
+          event = if state.is_down { RegEvent::SwitchDown } else { RegEvent::SwitchUp };
+        }
+      }
+      else {
+        state.pending = 0;
+      }
+
+      // the deep trigger has its own hysteresis, and is released before the
+      // switch itself
+      let is_deep = match settings.deep_trigger {
+        Some(deep) if state.is_down => match settings.polarity {
+          Polarity::S => if state.is_deep {
+            value <= deep.trig_up
+          } else {
+            value < deep.trig_down
+          },
+          Polarity::N => if state.is_deep {
+            value >= deep.trig_up
+          } else {
+            value > deep.trig_down
+          },
+        },
+        _ => false,
+      };
+      if event == RegEvent::SwitchDown {
+        events.push((i, event)).unwrap();
+      }
+      if is_deep != state.is_deep {
+        state.is_deep = is_deep;
+        let deep_event = if is_deep { RegEvent::DeepDown } else { RegEvent::DeepUp };
+        events.push((i, deep_event)).unwrap();
+      }
+      if event == RegEvent::SwitchUp {
+        events.push((i, event)).unwrap();
+      }
+      if !state.is_down {
+        self.baseline[i].track(raw, drift);
       }
     }
     return events;
-  }    
+  }
+
+  fn release(&mut self, i: usize, events: &mut Vec<(usize, RegEvent), MAX_REG_EVENTS>) {
+    let state = &mut self.state[i];
+    if state.is_deep {
+      events.push((i, RegEvent::DeepUp)).unwrap();
+    }
+    if state.is_down {
+      events.push((i, RegEvent::SwitchUp)).unwrap();
+    }
+    state.is_down = false;
+    state.is_deep = false;
+    state.extreme = 0;
+    state.pending = 0;
+  }
+
+  // release every switch, e.g. before their readings stop being reported
+  fn release_all(&mut self) -> Vec<(usize, RegEvent), MAX_REG_EVENTS> {
+    let mut events = Vec::<(usize, RegEvent), MAX_REG_EVENTS>::new();
+    for i in 0..BUS_WIDTH {
+      self.release(i, &mut events);
+    }
+    return events;
+  }
+
+  // whether no enabled switch reads past its release threshold, without
+  // changing any state
+  fn is_released(&self, values: RegValue) -> bool {
+    (0..BUS_WIDTH).filter(|&i| self.is_enabled[i]).all(|i| {
+      let settings = &self.state[i].fixed;
+      let value = values[i] as i32 - self.baseline[i].drift();
+      match settings.polarity {
+        Polarity::S => value > settings.trig_up,
+        Polarity::N => value < settings.trig_up,
+      }
+    })
+  }
+
+  // stop reporting a switch, releasing it first
+  fn disable(&mut self, i: usize) -> Vec<(usize, RegEvent), MAX_REG_EVENTS> {
+    let mut events = Vec::<(usize, RegEvent), MAX_REG_EVENTS>::new();
+    self.release(i, &mut events);
+    self.is_enabled[i] = false;
+    events
+  }
+}
+
+// readings in one press and release of the bench, and updates timed
+#[cfg(any(test, feature = "bench"))]
+const BENCH_STEPS: usize = 64;
+#[cfg(any(test, feature = "bench"))]
+const BENCH_UPDATES: usize = 10_000;
+
+// DriftSettings in normalized units, for the float reference
+#[cfg(any(test, feature = "bench"))]
+#[derive(Debug, Copy, Clone)]
+struct FloatDrift {
+  rate: f32,
+  band: f32,
+  max_drift: f32,
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl FloatDrift {
+  fn new(fixed: &FixedDriftSettings) -> Self {
+    Self {
+      rate: fixed.rate as f32 / 4_294_967_296.0,
+      band: fixed.band as f32 / ADC_MAX as f32,
+      max_drift: fixed.max_drift as f32 / ADC_MAX as f32,
+    }
+  }
+}
+
+// Hysteresis, deep trigger and drift tracking of one switch in floating
+// point, as RegState::update did before its thresholds were converted to ADC
+// counts. Kept only for `bench` to time against, the M0+ has no FPU and each
+// float operation is a library call, so it is left out of normal builds.
+#[cfg(any(test, feature = "bench"))]
+#[derive(Debug, Copy, Clone, Default)]
+struct FloatSwitch {
+  is_down: bool,
+  is_deep: bool,
+  // resting value at the first sample, and as tracked since
+  reference: Option<f32>,
+  baseline: f32,
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl FloatSwitch {
+  // returns whether the switch changed state
+  fn update(&mut self, raw: u16, settings: &SwitchSettings, drift: &FloatDrift) -> bool {
+    let raw = raw as f32 / ADC_MAX as f32;
+    let reference = match self.reference {
+      Some(reference) => reference,
+      None => {
+        self.baseline = raw;
+        *self.reference.insert(raw)
+      }
+    };
+    let value = raw - (self.baseline - reference);
+    let was_down = self.is_down;
+    self.is_down = match settings.polarity {
+      Polarity::S => if self.is_down { value <= settings.trig_up } else { value < settings.trig_down },
+      Polarity::N => if self.is_down { value >= settings.trig_up } else { value > settings.trig_down },
+    };
+    self.is_deep = match settings.deep_trigger {
+      Some(deep) if self.is_down => match settings.polarity {
+        Polarity::S => if self.is_deep { value <= deep.trig_up } else { value < deep.trig_down },
+        Polarity::N => if self.is_deep { value >= deep.trig_up } else { value > deep.trig_down },
+      },
+      _ => false,
+    };
+    if !self.is_down && (raw - self.baseline).abs() <= drift.band {
+      self.baseline += (raw - self.baseline) * drift.rate;
+      self.baseline = self.baseline.clamp(reference - drift.max_drift, reference + drift.max_drift);
+    }
+    self.is_down != was_down
+  }
+}
+
+// Durations of full matrix scans, and how late they started against their
+// schedule, measured by the caller
+#[derive(Debug, Copy, Clone)]
+pub struct ScanTime {
+  pub scans: u32,
+  pub total_us: u64,
+  pub min_us: u32,
+  pub max_us: u32,
+  pub total_late_us: u64,
+  pub max_late_us: u32,
+  pub missed: u32,
+}
+
+impl Default for ScanTime {
+  fn default() -> Self {
+    Self {
+      scans: 0, total_us: 0, min_us: u32::MAX, max_us: 0,
+      total_late_us: 0, max_late_us: 0, missed: 0,
+    }
+  }
+}
+
+impl ScanTime {
+  pub fn record(&mut self, us: u32) {
+    self.scans = self.scans.saturating_add(1);
+    self.total_us += us as u64;
+    self.min_us = self.min_us.min(us);
+    self.max_us = self.max_us.max(us);
+  }
+
+  // for scans on a schedule, before the matching `record`
+  pub fn record_start(&mut self, late_us: u32, missed: u32) {
+    self.total_late_us += late_us as u64;
+    self.max_late_us = self.max_late_us.max(late_us);
+    self.missed = self.missed.saturating_add(missed);
+  }
+
+  pub fn mean_late_us(&self) -> Option<u32> {
+    if self.scans == 0 {
+      return None;
+    }
+    Some((self.total_late_us / self.scans as u64) as u32)
+  }
+
+  pub fn mean_us(&self) -> Option<u32> {
+    if self.scans == 0 {
+      return None;
+    }
+    Some((self.total_us / self.scans as u64) as u32)
+  }
 }
 
 pub struct SwitchMatrix<Q: OutputPin> {
   reg_map: RegMap,
   sel_pins: [Q; SEL_WIDTH],
   reg_state: Vec<RegState, MAX_REGS>,
+  // applied on top of the board calibration for the active layer
+  overrides: Vec<ActuationOverride, MAX_OVERRIDES>,
+  layer: LayerIndex,
+  // running auto-calibration, if any
+  calibrator: Option<Calibrator>,
+  pub auto_calibration: CalibrationSettings,
+  filters: Vec<RegFilter, MAX_REGS>,
+  filter: FixedFilterSettings,
+  // running noise measurement, if any
+  noise_meter: Option<NoiseMeter>,
+  // durations in the drift and fault settings are converted at this rate
+  scan: ScanSettings,
+  drift_settings: DriftSettings,
+  drift: FixedDriftSettings,
+  // for keys without their own
+  debounce: Debounce,
+  // faulty switches are disabled
+  health: Vec<[ChannelHealth; BUS_WIDTH], MAX_REGS>,
+  fault_settings: FaultSettings,
+  faults: FixedFaultSettings,
+  pub scan_time: ScanTime,
+  // converts actuation points in mm, if the board names one
+  profile: Option<SwitchProfile>,
+  // confirms that hysteresis and debounce leave no chatter
+  chatter: ChatterDetector,
 }
 
 impl<Q: OutputPin> SwitchMatrix<Q> {
@@ -106,26 +442,345 @@ impl<Q: OutputPin> SwitchMatrix<Q> {
     for i in 0..reg_map.regs.len() {
       for j in 0..BUS_WIDTH {
         if reg_map.regs[i][j].is_some() {
+          // thresholds must leave room for hysteresis
+          if !reg_map.calibration[i][j].is_valid() {
+            return Err(Error::InvalidSettings);
+          }
           reg_state[i].is_enabled[j] = true;
         }
       }
     }
-    Ok(Self {reg_map, sel_pins: sel_pins.into(), reg_state})
+    let mut filters = Vec::new();
+    filters.resize_default(reg_map.regs.len()).unwrap();
+    let mut health = Vec::new();
+    health.resize_default(reg_map.regs.len()).unwrap();
+    Ok(Self {
+      reg_map, sel_pins: sel_pins.into(), reg_state, filters,
+      overrides: Vec::new(),
+      layer: 0,
+      calibrator: None,
+      auto_calibration: CalibrationSettings::default(),
+      filter: FilterSettings::default().to_fixed().unwrap(),
+      noise_meter: None,
+      scan: ScanSettings::default(),
+      drift_settings: DriftSettings::default(),
+      drift: DriftSettings::default().to_fixed(&ScanSettings::default()),
+      debounce: Debounce::default(),
+      health,
+      fault_settings: FaultSettings::default(),
+      faults: FaultSettings::default().to_fixed(&ScanSettings::default()),
+      scan_time: ScanTime::default(),
+      profile: None,
+      chatter: ChatterDetector::new(ChatterSettings::default()),
+    })
   }
 
   pub fn num_regs(&self) -> usize {
     self.reg_map.regs.len()
   }
 
-  pub fn subtick<D: DelayUs<u32>, B: AnalogBus>(
-    &mut self, i_reg: RegIndex, bus: &mut B, delay: &mut D,
-    write_fmt: impl Fn(core::fmt::Arguments) -> ())
-    -> Result<Vec<KeyEvent, BUS_WIDTH>, Error>
+  fn find_key(&self, key: KeyIndex) -> Option<(usize, usize)> {
+    for (i, reg) in self.reg_map.regs.iter().enumerate() {
+      if let Some(j) = reg.iter().position(|k| *k == Some(key)) {
+        return Some((i, j));
+      }
+    }
+    None
+  }
+
+  // current settings of a key, including overrides for the active layer
+  pub fn settings(&self, key: KeyIndex) -> Option<SwitchSettings> {
+    let (i, j) = self.find_key(key)?;
+    Some(self.reg_state[i].state[j].settings)
+  }
+
+  // movement of a key's resting value, in normalized units
+  pub fn drift(&self, key: KeyIndex) -> Option<f32> {
+    let (i, j) = self.find_key(key)?;
+    Some(self.reg_state[i].baseline[j].drift() as f32 / ADC_MAX as f32)
+  }
+
+  // Settings used while scanning are converted to fixed point once, here.
+  pub fn set_filter(&mut self, settings: FilterSettings) -> Result<(), Error> {
+    self.filter = settings.to_fixed()?;
+    Ok(())
+  }
+
+  pub fn set_drift(&mut self, settings: DriftSettings) {
+    self.drift_settings = settings;
+    self.drift = settings.to_fixed(&self.scan);
+  }
+
+  pub fn set_faults(&mut self, settings: FaultSettings) {
+    self.fault_settings = settings;
+    self.faults = settings.to_fixed(&self.scan);
+  }
+
+  // the rate core 1 scans at, settings given as times follow it
+  pub fn set_scan(&mut self, scan: ScanSettings) {
+    self.scan = scan;
+    self.drift = self.drift_settings.to_fixed(&scan);
+    self.faults = self.fault_settings.to_fixed(&scan);
+  }
+
+  pub fn set_chatter(&mut self, settings: ChatterSettings) {
+    self.chatter = ChatterDetector::new(settings);
+  }
+
+  pub fn chatter(&self) -> &ChatterDetector {
+    &self.chatter
+  }
+
+  pub fn reset_chatter(&mut self) {
+    self.chatter.reset();
+  }
+
+  pub fn set_debounce(&mut self, debounce: Debounce) {
+    self.debounce = debounce;
+    self.apply_settings();
+  }
+
+  pub fn has_faults(&self) -> bool {
+    self.health.iter().flatten().any(|h| h.fault.is_some())
+  }
+
+  pub fn faults(&self) -> Vec<(KeyIndex, Fault), MAX_KEYS> {
+    let mut faults = Vec::new();
+    for (regs, health) in self.reg_map.regs.iter().zip(self.health.iter()) {
+      for (key, h) in regs.iter().zip(health.iter()) {
+        if let (Some(key), Some(fault)) = (key, h.fault) {
+          faults.push((*key, fault)).unwrap();
+        }
+      }
+    }
+    faults
+  }
+
+  // re-enable a key disabled by a fault, e.g. after a repair
+  pub fn enable(&mut self, key: KeyIndex) -> Result<(), Error> {
+    let (i, j) = self.find_key(key).ok_or(Error::InvalidKey)?;
+    self.health[i][j] = ChannelHealth::default();
+    self.reg_state[i].baseline[j] = Baseline::default();
+    self.reg_state[i].is_enabled[j] = true;
+    Ok(())
+  }
+
+  pub fn overrides(&self) -> &[ActuationOverride] {
+    &self.overrides[..]
+  }
+
+  // whether `ov` would be valid on top of `calibration`, which need not be
+  // the one in use
+  fn check_override(
+    &self, ov: &ActuationOverride, calibration: &[[SwitchSettings; BUS_WIDTH]])
+    -> Result<(), Error>
   {
-    let reg_state = &mut self.reg_state[i_reg as usize];
-    let mut events = Vec::<KeyEvent, BUS_WIDTH>::new();
+    let (i, j) = self.find_key(ov.key).ok_or(Error::InvalidKey)?;
+    let settings = SwitchSettings {
+      trig_down: ov.trig_down,
+      trig_up: ov.trig_up,
+      ..calibration[i][j]
+    };
+    if !settings.is_valid() {
+      return Err(Error::InvalidSettings);
+    }
+    Ok(())
+  }
 
-    // guarded by bus lock
+  // Checks a calibration and overrides without applying either, so that
+  // callers can apply several settings all or nothing. An empty calibration
+  // stands for the current one.
+  pub fn check_settings(
+    &self, calibration: &[[SwitchSettings; BUS_WIDTH]], overrides: &[ActuationOverride])
+    -> Result<(), Error>
+  {
+    let calibration = if calibration.is_empty() {
+      &self.reg_map.calibration[..]
+    }
+    else {
+      if calibration.len() != self.reg_map.calibration.len() {
+        return Err(Error::SizeMismatch);
+      }
+      if !calibration.iter().flatten().all(|s| s.is_valid()) {
+        return Err(Error::InvalidSettings);
+      }
+      calibration
+    };
+    for ov in overrides.iter() {
+      self.check_override(ov, calibration)?;
+    }
+    Ok(())
+  }
+
+  // add an override, replacing any existing one for the same key and layer
+  pub fn set_override(&mut self, ov: ActuationOverride) -> Result<(), Error> {
+    self.check_override(&ov, &self.reg_map.calibration[..])?;
+    match self.overrides.iter().position(|o| o.key == ov.key && o.layer == ov.layer) {
+      Some(idx) => self.overrides[idx] = ov,
+      None => self.overrides.push(ov).map_err(|_| Error::VecOverflow)?,
+    }
+    self.apply_settings();
+    Ok(())
+  }
+
+  // replace all overrides, e.g. with stored ones
+  pub fn set_overrides(&mut self, overrides: &[ActuationOverride]) -> Result<(), Error> {
+    self.check_settings(&[], overrides)?;
+    self.overrides.clear();
+    for ov in overrides.iter() {
+      self.set_override(*ov)?;
+    }
+    Ok(())
+  }
+
+  // returns whether an override was removed
+  pub fn clear_override(&mut self, key: KeyIndex, layer: Option<LayerIndex>) -> bool {
+    let len = self.overrides.len();
+    self.overrides.retain(|o| !(o.key == key && o.layer == layer));
+    self.apply_settings();
+    self.overrides.len() != len
+  }
+
+  pub fn calibration(&self) -> &[[SwitchSettings; BUS_WIDTH]] {
+    &self.reg_map.calibration[..]
+  }
+
+  // replace the board calibration, e.g. with a stored one
+  pub fn set_calibration(
+    &mut self, calibration: &[[SwitchSettings; BUS_WIDTH]]) -> Result<(), Error>
+  {
+    if calibration.is_empty() {
+      return Err(Error::SizeMismatch);
+    }
+    self.check_settings(calibration, &[])?;
+    self.reg_map.calibration.clear();
+    self.reg_map.calibration.extend_from_slice(calibration).unwrap();
+    for reg in self.reg_state.iter_mut() {
+      reg.reset_baselines();
+    }
+    self.apply_settings();
+    Ok(())
+  }
+
+  pub fn is_calibrating(&self) -> bool {
+    self.calibrator.is_some()
+  }
+
+  // Switches report no events until calibration finishes.
+  pub fn start_calibration(&mut self, write_fmt: impl Fn(core::fmt::Arguments) -> ()) {
+    self.calibrator = Some(Calibrator::new(self.num_regs(), self.auto_calibration, &self.scan));
+    write_fmt(format_args!(
+      "Calibrating: release all keys, then press each key to the bottom.\r\n"));
+  }
+
+  // Apply measured thresholds as the new board calibration, returns the
+  // number of keys calibrated.
+  pub fn finish_calibration(&mut self, write_fmt: impl Fn(core::fmt::Arguments) -> ()) -> usize {
+    let cal = match self.calibrator.take() {
+      Some(cal) => cal,
+      None => return 0,
+    };
+    let mut n_keys = 0;
+    for i in 0..self.reg_map.regs.len() {
+      for j in 0..BUS_WIDTH {
+        let key = match self.reg_map.regs[i][j] {
+          Some(key) => key,
+          None => continue,
+        };
+        match cal.switch_settings(i, j, self.reg_map.calibration[i][j]) {
+          Some(settings) => {
+            self.reg_map.calibration[i][j] = settings;
+            self.reg_state[i].baseline[j] = Baseline::default();
+            n_keys += 1;
+            write_fmt(format_args!(
+              "key {}: {:?} down {:.3} up {:.3}\r\n",
+              key, settings.polarity, settings.trig_down, settings.trig_up));
+          }
+          None => write_fmt(format_args!("key {}: not calibrated\r\n", key)),
+        }
+      }
+    }
+    self.apply_settings();
+    write_fmt(format_args!("Calibrated {} keys.\r\n", n_keys));
+    n_keys
+  }
+
+  pub fn start_noise_measurement(&mut self, write_fmt: impl Fn(core::fmt::Arguments) -> ()) {
+    self.noise_meter = Some(NoiseMeter::new(self.num_regs(), &self.scan));
+    write_fmt(format_args!("Measuring noise: leave all keys released.\r\n"));
+  }
+
+  // Report the spread of each key's filtered readings, and compare the worst
+  // against the narrowest hysteresis band in use.
+  fn finish_noise_measurement(&mut self, write_fmt: impl Fn(core::fmt::Arguments) -> ()) {
+    let meter = match self.noise_meter.take() {
+      Some(meter) => meter,
+      None => return,
+    };
+    let mut worst_std_dev: f32 = 0.0;
+    let mut worst_peak_to_peak = 0;
+    let mut min_hysteresis = f32::MAX;
+    for i in 0..self.reg_map.regs.len() {
+      for j in 0..BUS_WIDTH {
+        let (key, noise) = match (self.reg_map.regs[i][j], meter.noise(i, j)) {
+          (Some(key), Some(noise)) => (key, noise),
+          _ => continue,
+        };
+        write_fmt(format_args!(
+          "key {}: mean {:.0} sd {:.2} p2p {}\r\n",
+          key, noise.mean, noise.std_dev, noise.peak_to_peak));
+        worst_std_dev = worst_std_dev.max(noise.std_dev);
+        worst_peak_to_peak = worst_peak_to_peak.max(noise.peak_to_peak);
+        let settings = self.reg_state[i].state[j].settings;
+        min_hysteresis = min_hysteresis.min((settings.trig_up - settings.trig_down).abs());
+      }
+    }
+    write_fmt(format_args!(
+      "Noise floor: sd {:.2} p2p {} ({:.4} normalized), narrowest hysteresis {:.4}\r\n",
+      worst_std_dev, worst_peak_to_peak,
+      worst_peak_to_peak as f32 / ADC_MAX as f32, min_hysteresis));
+  }
+
+  pub fn profile(&self) -> Option<&SwitchProfile> {
+    self.profile.as_ref()
+  }
+
+  pub fn set_profile(&mut self, profile: Option<SwitchProfile>) {
+    self.profile = profile;
+    self.apply_settings();
+  }
+
+  pub fn set_layer(&mut self, layer: LayerIndex) {
+    if layer != self.layer {
+      self.layer = layer;
+      self.apply_settings();
+    }
+  }
+
+  fn apply_settings(&mut self) {
+    for i in 0..self.reg_map.regs.len() {
+      for j in 0..BUS_WIDTH {
+        let mut settings = self.reg_map.calibration[i][j];
+        settings.debounce = settings.debounce.or(Some(self.debounce));
+        if let Some(profile) = self.profile.as_ref() {
+          settings = profile.apply(settings);
+        }
+        if let Some(key) = self.reg_map.regs[i][j] {
+          let all_layers = self.overrides.iter()
+            .find(|o| o.key == key && o.layer.is_none());
+          let this_layer = self.overrides.iter()
+            .find(|o| o.key == key && o.layer == Some(self.layer));
+          if let Some(ov) = this_layer.or(all_layers) {
+            settings.trig_down = ov.trig_down;
+            settings.trig_up = ov.trig_up;
+          }
+        }
+        self.reg_state[i].state[j].set_settings(settings);
+      }
+    }
+  }
+
+  fn select<D: DelayUs<u32>>(&mut self, i_reg: RegIndex, delay: &mut D) -> Result<(), Error> {
     for i in 0..SEL_WIDTH {
       if (i_reg >> i) % 2 == 0 {
         self.sel_pins[i].set_low().map_err(|_| Error::PinConfigError)?;
@@ -134,25 +789,367 @@ impl<Q: OutputPin> SwitchMatrix<Q> {
         self.sel_pins[i].set_high().map_err(|_| Error::PinConfigError)?;
       }
     }
+    // mux settling time
     delay.delay_us(2);
-    // TODO: multisampling to reduce noise
-    let read_values = bus.read();
+    Ok(())
+  }
+
+  // Check raw readings of every register at boot, with all keys released, and
+  // disable faulty keys. Returns the number disabled.
+  pub fn self_test<D: DelayUs<u32>, B: AnalogBus>(
+    &mut self, bus: &mut B, delay: &mut D,
+    write_fmt: impl Fn(core::fmt::Arguments) -> ())
+    -> Result<usize, Error>
+  {
+    let settings = FixedFaultSettings {
+      rail_samples: SELF_TEST_SAMPLES - 1,
+      flat_samples: SELF_TEST_SAMPLES - 1,
+      ..self.faults
+    };
+    let mut n_faults = 0;
+    for i in 0..self.num_regs() {
+      self.select(i as RegIndex, delay)?;
+      let mut health = [ChannelHealth::default(); BUS_WIDTH];
+      for _ in 0..SELF_TEST_SAMPLES {
+        let values = bus.read();
+        for j in 0..BUS_WIDTH {
+          // a failed conversion says nothing about the sensor
+          if let Some(value) = values[j] {
+            health[j].check(value, &settings);
+          }
+        }
+      }
+      for j in 0..BUS_WIDTH {
+        let (key, fault) = match (self.reg_map.regs[i][j], health[j].fault) {
+          (Some(key), Some(fault)) => (key, fault),
+          _ => continue,
+        };
+        self.health[i][j].fault = Some(fault);
+        self.reg_state[i].disable(j);
+        n_faults += 1;
+        write_fmt(format_args!("key {}: {:?} fault, disabled\r\n", key, fault));
+      }
+    }
+    write_fmt(format_args!("Self-test found {} faulty keys.\r\n", n_faults));
+    Ok(n_faults)
+  }
+
+  // Times RegState::update against the float reference on the same readings,
+  // sweeps over the full ADC range with the settings of the first register.
+  // Scanning stops meanwhile, for a few tens of ms.
+  #[cfg(any(test, feature = "bench"))]
+  pub fn bench<C: Clock>(&self, clock: &C, write_fmt: impl Fn(core::fmt::Arguments) -> ()) {
+    let settings: [SwitchSettings; BUS_WIDTH] = match self.reg_state.first() {
+      Some(reg) => core::array::from_fn(|j| reg.state[j].settings),
+      None => return,
+    };
+    let mut readings = [[0u16; BUS_WIDTH]; BENCH_STEPS];
+    for (k, reading) in readings.iter_mut().enumerate() {
+      let t = if k < BENCH_STEPS / 2 { k } else { BENCH_STEPS - k };
+      *reading = [(t * (ADC_MAX as usize - 1) / (BENCH_STEPS / 2)) as u16; BUS_WIDTH];
+    }
+
+    let mut reg = RegState::new(settings);
+    reg.is_enabled = [true; BUS_WIDTH];
+    let mut fixed_events = 0;
+    let start = clock.now();
+    for k in 0..BENCH_UPDATES {
+      let reading = core::hint::black_box(readings[k % BENCH_STEPS]);
+      fixed_events += reg.update(reading, &self.drift).len();
+    }
+    let fixed_us = clock.now() - start;
+
+    let drift = FloatDrift::new(&self.drift);
+    let mut switches = [FloatSwitch::default(); BUS_WIDTH];
+    let mut float_events = 0;
+    let start = clock.now();
+    for k in 0..BENCH_UPDATES {
+      let reading = core::hint::black_box(readings[k % BENCH_STEPS]);
+      for j in 0..BUS_WIDTH {
+        if switches[j].update(reading[j], &settings[j], &drift) {
+          float_events += 1;
+        }
+      }
+    }
+    let float_us = clock.now() - start;
+    core::hint::black_box((fixed_events, float_events));
+
+    let per_update_ns = |us: u64| us * 1000 / BENCH_UPDATES as u64;
+    write_fmt(format_args!(
+      "{} register updates: fixed {} ns, float {} ns each\r\n",
+      BENCH_UPDATES, per_update_ns(fixed_us), per_update_ns(float_us)));
+    if fixed_us > 0 {
+      let ratio = float_us * 100 / fixed_us;
+      write_fmt(format_args!("fixed is {}.{:02}x as fast\r\n", ratio / 100, ratio % 100));
+    }
+  }
+
+  pub fn subtick<D: DelayUs<u32>, B: AnalogBus, C: Clock>(
+    &mut self, i_reg: RegIndex, bus: &mut B, delay: &mut D, clock: &C,
+    events: &mut EventQueue, write_fmt: impl Fn(core::fmt::Arguments) -> ())
+    -> Result<(), Error>
+  {
+    // guarded by bus lock
+    self.select(i_reg, delay)?;
+    let reg_state = &mut self.reg_state[i_reg as usize];
+    let (raw_values, read_values) = self.filters[i_reg as usize].read(bus, &self.filter);
+    // events are stamped with the time the register was read
+    let time = clock.now();
+    if let Some(meter) = self.noise_meter.as_mut() {
+      meter.sample(i_reg as usize, read_values);
+    }
     // write_fmt(format_args!("Read values {}: {:?}\r\n", i_reg, read_values));
-    let reg_events = reg_state.update(read_values);
-    delay.delay_us(1);
+    let mut reg_events = Vec::<(usize, RegEvent), MAX_REG_EVENTS>::new();
+    match self.calibrator.as_mut() {
+      Some(cal) => {
+        cal.sample(i_reg as usize, read_values, reg_state.is_released(read_values));
+        reg_events = reg_state.release_all();
+      }
+      None => {
+        for (j, health) in self.health[i_reg as usize].iter_mut().enumerate() {
+          if !reg_state.is_enabled[j] {
+            continue;
+          }
+          // on raw readings, since filtering hides noise and smooths jumps
+          let raw = match raw_values[j] {
+            Some(raw) => raw,
+            None => continue,
+          };
+          if let Some(fault) = health.check(raw, &self.faults) {
+            reg_events.extend(reg_state.disable(j));
+            if let Some(key) = self.reg_map.regs[i_reg as usize][j] {
+              write_fmt(format_args!("key {}: {:?} fault, disabled\r\n", key, fault));
+            }
+          }
+        }
+        reg_events.extend(reg_state.update(read_values, &self.drift));
+      }
+    }
 
-    for i in 0..BUS_WIDTH {
+    for (i, reg_event) in reg_events.into_iter() {
       let key: KeyIndex = match self.reg_map.regs[i_reg as usize][i] {
         Some(key) => key,
         None => continue,
       };
-      match reg_events[i] {
-        RegEvent::None => {},
-        RegEvent::SwitchUp => events.push(KeyEvent::Up(key)).map_err(|_| Error::VecOverflow)?,
-        RegEvent::SwitchDown => events.push(KeyEvent::Down(key)).map_err(|_| Error::VecOverflow)?,
-      }
+      let change = match reg_event {
+        RegEvent::None => continue,
+        RegEvent::SwitchUp => {
+          self.chatter.update(key, false, to_ms(time));
+          KeyChange::Up(key)
+        }
+        RegEvent::SwitchDown => {
+          self.chatter.update(key, true, to_ms(time));
+          KeyChange::Down(key)
+        }
+        RegEvent::DeepUp => KeyChange::DeepUp(key),
+        RegEvent::DeepDown => KeyChange::DeepDown(key),
+      };
+      // overflow is counted by the queue, scanning carries on
+      events.push(KeyEvent { change, time }).ok();
+    }
+
+    if self.calibrator.as_ref().map_or(false, |cal| cal.is_done()) {
+      self.finish_calibration(&write_fmt);
     }
+    if self.noise_meter.as_ref().map_or(false, |meter| meter.is_done()) {
+      self.finish_noise_measurement(&write_fmt);
+    }
+
+    return Ok(());
+  }
+}
 
-    return Ok(events);
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::board::{RapidTrigger, DeepTrigger};
+
+  fn feed(reg: &mut RegState, values: &[f32]) -> Vec<bool, 32> {
+    values.iter().map(|v| {
+      reg.update([(v * ADC_MAX as f32) as u16; BUS_WIDTH], &DriftSettings::default().to_fixed(&ScanSettings::default()));
+      reg.state[0].is_down
+    }).collect()
+  }
+
+  fn rapid_reg(polarity: Polarity, trig_down: f32, trig_up: f32) -> RegState {
+    let settings = SwitchSettings {
+      polarity, trig_down, trig_up,
+      rapid_trigger: Some(RapidTrigger { release: 0.05, press: 0.05 }),
+      ..SwitchSettings::default()
+    };
+    let mut reg = RegState::new([settings; BUS_WIDTH]);
+    reg.is_enabled[0] = true;
+    reg
+  }
+
+  #[test]
+  fn rapid_trigger_south() {
+    let mut reg = rapid_reg(Polarity::S, 0.35, 0.4);
+    let down = feed(&mut reg, &[0.9, 0.38, 0.3, 0.2, 0.24, 0.26, 0.2, 0.3, 0.39, 0.45, 0.38, 0.34]);
+    assert_eq!(&down[..], &[false, false, true, true, true, false, true, false, false, false, false, true]);
+  }
+
+  #[test]
+  fn rapid_trigger_north() {
+    let mut reg = rapid_reg(Polarity::N, 0.65, 0.6);
+    let down = feed(&mut reg, &[0.1, 0.62, 0.7, 0.8, 0.76, 0.74, 0.8, 0.7, 0.61, 0.55, 0.62, 0.66]);
+    assert_eq!(&down[..], &[false, false, true, true, true, false, true, false, false, false, false, true]);
+  }
+
+  #[test]
+  fn deep_trigger_order() {
+    let settings = SwitchSettings {
+      deep_trigger: Some(DeepTrigger { trig_down: 0.1, trig_up: 0.15 }),
+      ..SwitchSettings::default()
+    };
+    let mut reg = RegState::new([settings; BUS_WIDTH]);
+    reg.is_enabled[0] = true;
+    let mut update = |v: f32| {
+      reg.update([(v * ADC_MAX as f32) as u16; BUS_WIDTH], &DriftSettings::default().to_fixed(&ScanSettings::default()))
+        .into_iter()
+        .map(|(_, e)| e).collect::<Vec<RegEvent, MAX_REG_EVENTS>>()
+    };
+    assert_eq!(&update(0.05)[..], &[RegEvent::SwitchDown, RegEvent::DeepDown]);
+    assert_eq!(&update(0.12)[..], &[]);
+    assert_eq!(&update(0.2)[..], &[RegEvent::DeepUp]);
+    assert_eq!(&update(0.05)[..], &[RegEvent::DeepDown]);
+    assert_eq!(&update(0.9)[..], &[RegEvent::DeepUp, RegEvent::SwitchUp]);
+  }
+
+  #[test]
+  fn debounce_samples() {
+    let settings = SwitchSettings {
+      debounce: Some(Debounce { press_samples: 3, release_samples: 1 }),
+      ..SwitchSettings::default()
+    };
+    let mut reg = RegState::new([settings; BUS_WIDTH]);
+    reg.is_enabled[0] = true;
+    // a two-sample spike is ignored, the third sample in a row presses
+    let down = feed(&mut reg, &[0.5, 0.05, 0.05, 0.5, 0.05, 0.05, 0.05, 0.05, 0.5]);
+    assert_eq!(&down[..], &[false, false, false, false, false, false, true, true, false]);
+    // the defaults act on the first sample
+    let mut reg = RegState::new([SwitchSettings::default(); BUS_WIDTH]);
+    reg.is_enabled[0] = true;
+    assert_eq!(&feed(&mut reg, &[0.5, 0.05, 0.5])[..], &[false, true, false]);
+  }
+
+  struct NoPin;
+
+  impl OutputPin for NoPin {
+    type Error = Infallible;
+    fn set_low(&mut self) -> Result<(), Infallible> {
+      Ok(())
+    }
+    fn set_high(&mut self) -> Result<(), Infallible> {
+      Ok(())
+    }
+  }
+
+  // one register holding keys 0 and 1, with the default settings
+  fn matrix() -> SwitchMatrix<NoPin> {
+    let mut reg_map = RegMap { regs: Vec::new(), calibration: Vec::new() };
+    reg_map.regs.push([Some(0), Some(1), None, None]).unwrap();
+    reg_map.calibration.push([SwitchSettings::default(); BUS_WIDTH]).unwrap();
+    SwitchMatrix::new(reg_map, core::array::from_fn(|_| NoPin)).unwrap()
+  }
+
+  fn trigs(switches: &SwitchMatrix<NoPin>, key: KeyIndex) -> (f32, f32) {
+    let settings = switches.settings(key).unwrap();
+    (settings.trig_down, settings.trig_up)
+  }
+
+  #[test]
+  fn override_precedence() {
+    let mut switches = matrix();
+    let ov = |layer, trig_down| ActuationOverride { key: 0, layer, trig_down, trig_up: 0.45 };
+    assert_eq!(trigs(&switches, 0), (0.1, 0.4));
+    // all layers beats the calibration
+    switches.set_override(ov(None, 0.2)).unwrap();
+    assert_eq!(trigs(&switches, 0), (0.2, 0.45));
+    // a layer beats all layers, but only while it is active
+    switches.set_override(ov(Some(1), 0.3)).unwrap();
+    assert_eq!(trigs(&switches, 0), (0.2, 0.45));
+    switches.set_layer(1);
+    assert_eq!(trigs(&switches, 0), (0.3, 0.45));
+    assert_eq!(trigs(&switches, 1), (0.1, 0.4));
+    switches.set_layer(2);
+    assert_eq!(trigs(&switches, 0), (0.2, 0.45));
+    // replacing an override keeps one per key and layer
+    switches.set_override(ov(Some(1), 0.35)).unwrap();
+    assert_eq!(switches.overrides().len(), 2);
+    switches.set_layer(1);
+    assert_eq!(trigs(&switches, 0), (0.35, 0.45));
+  }
+
+  #[test]
+  fn clear_restores_base() {
+    let mut switches = matrix();
+    switches.set_override(ActuationOverride { key: 0, layer: None, trig_down: 0.2, trig_up: 0.45 })
+      .unwrap();
+    switches.set_override(ActuationOverride { key: 0, layer: Some(1), trig_down: 0.3, trig_up: 0.45 })
+      .unwrap();
+    switches.set_layer(1);
+    assert!(switches.clear_override(0, Some(1)));
+    assert_eq!(trigs(&switches, 0), (0.2, 0.45));
+    assert!(!switches.clear_override(0, Some(1)));
+    assert!(switches.clear_override(0, None));
+    assert_eq!(trigs(&switches, 0), (0.1, 0.4));
+    // a new calibration shows through once no override covers it
+    let settings = SwitchSettings { trig_down: 0.15, trig_up: 0.35, ..Default::default() };
+    switches.set_calibration(&[[settings; BUS_WIDTH]]).unwrap();
+    assert_eq!(trigs(&switches, 0), (0.15, 0.35));
+    // an override that does not fit the calibration changes nothing
+    let bad = ActuationOverride { key: 0, layer: None, trig_down: 0.5, trig_up: 0.2 };
+    assert!(switches.set_overrides(&[bad]).is_err());
+    assert_eq!(trigs(&switches, 0), (0.15, 0.35));
+  }
+
+  #[test]
+  fn float_reference_agrees() {
+    let settings = SwitchSettings::default();
+    let drift = DriftSettings::default().to_fixed(&ScanSettings::default());
+    let mut reg = RegState::new([settings; BUS_WIDTH]);
+    reg.is_enabled[0] = true;
+    let mut switch = FloatSwitch::default();
+    for k in 0..4 * BENCH_STEPS {
+      let t = k % BENCH_STEPS;
+      let t = if t < BENCH_STEPS / 2 { t } else { BENCH_STEPS - t };
+      let value = (t * (ADC_MAX as usize - 1) / (BENCH_STEPS / 2)) as u16;
+      reg.update([value; BUS_WIDTH], &drift);
+      switch.update(value, &settings, &FloatDrift::new(&drift));
+      assert_eq!(reg.state[0].is_down, switch.is_down, "at {}", value);
+    }
+  }
+
+  #[test]
+  fn baseline_follows_rest() {
+    // a weight of 0.01 per sample at 1 kHz
+    let drift = DriftSettings { time_constant_s: 0.1, band: 0.02, max_drift: 0.05 }
+      .to_fixed(&ScanSettings { rate_hz: 1000 });
+    let mut reg = RegState::new([SwitchSettings::default(); BUS_WIDTH]);
+    reg.is_enabled[0] = true;
+    let mut feed = |v: f32, n: usize| {
+      for _ in 0..n {
+        reg.update([(v * ADC_MAX as f32) as u16; BUS_WIDTH], &drift);
+      }
+      (reg.state[0].is_down, reg.baseline[0].drift() as f32 / ADC_MAX as f32)
+    };
+    feed(0.5, 1);
+    // rest creeps up, so a press to 0.12 is past trig_down (0.1)
+    for rest in [0.51, 0.52, 0.53] {
+      feed(rest, 1000);
+    }
+    let (down, drift_before) = feed(0.12, 1);
+    assert!(down && (drift_before - 0.03).abs() < 0.001);
+    // holding the key does not move the baseline
+    assert_eq!(feed(0.12, 1000), (true, drift_before));
+    // and it is clamped when the rest keeps moving
+    feed(0.53, 1);
+    for rest in [0.54, 0.55, 0.56, 0.57, 0.58] {
+      feed(rest, 1000);
+    }
+    let (_, drift_after) = feed(0.58, 1);
+    // to within a count
+    assert!((drift_after - 0.05).abs() < 1.0 / ADC_MAX as f32);
   }
 }
@@ -1,9 +1,11 @@
-use heapless::Vec;
+use usb_device::class_prelude::*;
 use usbd_hid::descriptor::generator_prelude::*;
+use usbd_hid::hid_class::{
+  HIDClass, HidClassSettings, HidCountryCode, HidProtocol, HidProtocolMode,
+  HidSubClass, ProtocolModeConfig,
+};
 
-use crate::Error;
 use crate::prelude::*;
-use crate::vkeyboard::VKeyboard;
 
 // from usbd_hid v0.7.0
 // in the future, we can replace this with
@@ -231,12 +233,49 @@ pub use usbd_hid::descriptor::KeyboardUsage;
 //   KeyboardRightGUI = 231,
 //   Reserved = 232,
 // }
-
-
 const _: () = assert!(USB_CLASS_HID == 3, "USB class must be keyboard");
 // NOTE: must be in sync with HID descriptor
 const _: () = assert!(NKRO_MIN_KEY == 0x02, "Incorrect min usage");
 
+// Standard boot keyboard report descriptor (HID 1.11, appendix B.1). BIOS and
+// bootloader hosts skip the descriptor and assume this exact layout, so it is
+// written out by hand instead of generated.
+pub const BOOT_KEYBOARD_DESC: &[u8] = &[
+  0x05, 0x01, // Usage Page (Generic Desktop)
+  0x09, 0x06, // Usage (Keyboard)
+  0xa1, 0x01, // Collection (Application)
+  0x05, 0x07, //   Usage Page (Keyboard)
+  0x19, 0xe0, //   Usage Minimum (0xe0)
+  0x29, 0xe7, //   Usage Maximum (0xe7)
+  0x15, 0x00, //   Logical Minimum (0)
+  0x25, 0x01, //   Logical Maximum (1)
+  0x75, 0x01, //   Report Size (1)
+  0x95, 0x08, //   Report Count (8)
+  0x81, 0x02, //   Input (Data, Variable, Absolute): modifier byte
+  0x95, 0x01, //   Report Count (1)
+  0x75, 0x08, //   Report Size (8)
+  0x81, 0x01, //   Input (Constant): reserved byte
+  0x95, 0x05, //   Report Count (5)
+  0x75, 0x01, //   Report Size (1)
+  0x05, 0x08, //   Usage Page (LEDs)
+  0x19, 0x01, //   Usage Minimum (Num Lock)
+  0x29, 0x05, //   Usage Maximum (Kana)
+  0x91, 0x02, //   Output (Data, Variable, Absolute): LED report
+  0x95, 0x01, //   Report Count (1)
+  0x75, 0x03, //   Report Size (3)
+  0x91, 0x01, //   Output (Constant): LED report padding
+  0x95, 0x06, //   Report Count (6)
+  0x75, 0x08, //   Report Size (8)
+  0x15, 0x00, //   Logical Minimum (0)
+  0x25, 0x65, //   Logical Maximum (101)
+  0x05, 0x07, //   Usage Page (Keyboard)
+  0x19, 0x00, //   Usage Minimum (0)
+  0x29, 0x65, //   Usage Maximum (101)
+  0x81, 0x00, //   Input (Data, Array): key array
+  0xc0,       // End Collection
+];
+pub const BOOT_REPORT_LEN: usize = 8;
+
 #[gen_hid_descriptor(
   (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = KEYBOARD) = {
     (usage_page = KEYBOARD, usage_min = 0xE0, usage_max = 0xE7) = {
@@ -244,19 +283,11 @@ const _: () = assert!(NKRO_MIN_KEY == 0x02, "Incorrect min usage");
       #[item_settings data,variable,absolute]
       modifier = input;
     };
-    (usage_min = 0x00, usage_max = 0xFF) = {
-      #[item_settings constant,array,absolute]
-      reserved = input;
-    };
     (usage_page = LEDS, usage_min = 0x01, usage_max = 0x05) = {
       #[packed_bits 5]
       #[item_settings data,variable,absolute]
       leds = output;
     };
-    (usage_page = KEYBOARD, usage_min = 0x00, usage_max = 0xDD) = {
-      #[item_settings data,array,absolute]
-      boot_keys = input;
-    };
     (usage_page = KEYBOARD, usage_min = 0x02, usage_max = 0x81) = {
       #[packed_bits 128]
       #[item_settings data,variable,absolute]
@@ -265,16 +296,139 @@ const _: () = assert!(NKRO_MIN_KEY == 0x02, "Incorrect min usage");
   }
 )]
 #[derive(Default)]
-pub struct NKROBootKeyboardReport {
-  // fixed boot format report
+pub struct NKROKeyboardReport {
   pub modifier: u8,
-  pub reserved: u8,
   pub leds: u8,
+  pub nkro_keys: [u8; 16],
+}
+
+// Host lock state from the LED output report (HID usage page 0x08)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HostLeds(pub u8);
+
+impl HostLeds {
+  pub fn num_lock(&self) -> bool {
+    self.0 & 0x01 != 0
+  }
+  pub fn caps_lock(&self) -> bool {
+    self.0 & 0x02 != 0
+  }
+  pub fn scroll_lock(&self) -> bool {
+    self.0 & 0x04 != 0
+  }
+  pub fn compose(&self) -> bool {
+    self.0 & 0x08 != 0
+  }
+  pub fn kana(&self) -> bool {
+    self.0 & 0x10 != 0
+  }
+}
+
+// Logical keyboard state, rendered into a boot or NKRO report depending on the
+// protocol selected by the host.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct NKROBootKeyboardReport {
+  pub modifier: u8,
   pub boot_keys: [u8; 6],
-  // nkro extension for USB-compatible OS
   pub nkro_keys: [u8; 16],
 }
 
+impl NKROBootKeyboardReport {
+  pub fn boot_report(&self) -> [u8; BOOT_REPORT_LEN] {
+    let mut report = [0; BOOT_REPORT_LEN];
+    report[0] = self.modifier;
+    report[2..].copy_from_slice(&self.boot_keys);
+    report
+  }
+
+  pub fn nkro_report(&self) -> NKROKeyboardReport {
+    NKROKeyboardReport {
+      modifier: self.modifier,
+      leds: 0,
+      nkro_keys: self.nkro_keys,
+    }
+  }
+}
+
+// Composite keyboard with a standards-compliant boot interface and a separate
+// NKRO interface. Following the HID spec, the host picks the protocol with
+// SET_PROTOCOL on the boot interface:
+// - Boot protocol (BIOS, GRUB): 6KRO reports go out on the boot interface
+// - Report protocol (the default after reset): NKRO reports go out on the NKRO
+//   interface and the boot interface stays silent, so keys are never
+//   reported twice
+pub struct KeyboardInterface<'a, B: UsbBus> {
+  pub boot_class: HIDClass<'a, B>,
+  pub nkro_class: HIDClass<'a, B>,
+  last_boot: bool,
+}
+
+impl<'a, B: UsbBus> KeyboardInterface<'a, B> {
+  pub fn new(usb_bus: &'a UsbBusAllocator<B>, poll_ms: u8) -> Self {
+    let boot_class = HIDClass::new_with_settings(
+      usb_bus, BOOT_KEYBOARD_DESC, poll_ms,
+      HidClassSettings {
+        subclass: HidSubClass::Boot,
+        protocol: HidProtocol::Keyboard,
+        config: ProtocolModeConfig::DefaultBehavior,
+        locale: HidCountryCode::US,
+      });
+    let nkro_class = HIDClass::new_with_settings(
+      usb_bus, NKROKeyboardReport::desc(), poll_ms,
+      HidClassSettings {
+        subclass: HidSubClass::NoSubClass,
+        protocol: HidProtocol::Generic,
+        config: ProtocolModeConfig::DefaultBehavior,
+        locale: HidCountryCode::US,
+      });
+    Self { boot_class, nkro_class, last_boot: false }
+  }
+
+  pub fn is_boot_protocol(&self) -> bool {
+    matches!(self.boot_class.get_protocol_mode(), Ok(HidProtocolMode::Boot))
+  }
+
+  // Both interfaces declare the LED output report, so the host may update the
+  // lock state through either. Returns the most recent report, if any.
+  pub fn pull_leds(&mut self) -> Result<Option<HostLeds>, UsbError> {
+    let mut buf: [u8; 64] = [0; 64];
+    let mut leds = None;
+    for kbd_class in [&self.boot_class, &self.nkro_class] {
+      match kbd_class.pull_raw_output(&mut buf) {
+        Ok(size) if size > 0 => {
+          leds = Some(HostLeds(buf[0] & 0x1f));
+        }
+        Ok(_) => {},
+        Err(UsbError::WouldBlock) => {}, // no data
+        Err(err) => return Err(err),
+      }
+    }
+    Ok(leds)
+  }
+
+  pub fn push_report(&mut self, report: &NKROBootKeyboardReport) -> Result<(), UsbError> {
+    let boot = self.is_boot_protocol();
+    if boot != self.last_boot {
+      // release all keys on the interface the host stopped listening to; the
+      // host may already refuse reports there, so errors are ignored
+      if self.last_boot {
+        self.boot_class.push_raw_input(&[0; BOOT_REPORT_LEN]).ok();
+      }
+      else {
+        self.nkro_class.push_input(&NKROKeyboardReport::default()).ok();
+      }
+      self.last_boot = boot;
+    }
+    if boot {
+      self.boot_class.push_raw_input(&report.boot_report())?;
+    }
+    else {
+      self.nkro_class.push_input(&report.nkro_report())?;
+    }
+    Ok(())
+  }
+}
+
 pub enum KeyUsageAndIndex {
   Normal {
     // bios usage and nkro index
@@ -306,3 +460,45 @@ impl KeyUsageAndIndex {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn contains(desc: &[u8], items: &[u8]) -> bool {
+    desc.windows(items.len()).any(|w| w == items)
+  }
+
+  #[test]
+  fn boot_desc_matches_spec() {
+    assert_eq!(BOOT_KEYBOARD_DESC.len(), 63);
+    assert_eq!(&BOOT_KEYBOARD_DESC[..6], &[0x05, 0x01, 0x09, 0x06, 0xa1, 0x01]);
+    assert_eq!(BOOT_KEYBOARD_DESC[BOOT_KEYBOARD_DESC.len()-1], 0xc0);
+    // 8 modifier bits, reserved byte, 5 LEDs + 3 padding, 6 key bytes
+    assert!(contains(BOOT_KEYBOARD_DESC, &[0x75, 0x01, 0x95, 0x08, 0x81, 0x02]));
+    assert!(contains(BOOT_KEYBOARD_DESC, &[0x95, 0x01, 0x75, 0x08, 0x81, 0x01]));
+    assert!(contains(BOOT_KEYBOARD_DESC, &[0x29, 0x05, 0x91, 0x02]));
+    assert!(contains(BOOT_KEYBOARD_DESC, &[0x75, 0x03, 0x91, 0x01]));
+    assert!(contains(BOOT_KEYBOARD_DESC, &[0x95, 0x06, 0x75, 0x08]));
+  }
+
+  #[test]
+  fn nkro_desc_is_keyboard() {
+    let desc = NKROKeyboardReport::desc();
+    assert_eq!(&desc[..6], &[0x05, 0x01, 0x09, 0x06, 0xa1, 0x01]);
+    assert_eq!(desc[desc.len()-1], 0xc0);
+    // LED output report and the NKRO bitmap starting at NKRO_MIN_KEY
+    assert!(contains(desc, &[0x05, 0x08]));
+    assert!(contains(desc, &[0x19, NKRO_MIN_KEY]));
+  }
+
+  #[test]
+  fn boot_report_layout() {
+    let report = NKROBootKeyboardReport {
+      modifier: 0x22,
+      boot_keys: [0x04, 0x05, 0, 0, 0, 0],
+      nkro_keys: [0; 16],
+    };
+    assert_eq!(report.boot_report(), [0x22, 0, 0x04, 0x05, 0, 0, 0, 0]);
+  }
+}
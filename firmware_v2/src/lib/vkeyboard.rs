@@ -3,7 +3,9 @@ use core::result::Result;
 use core::fmt;
 
 use crate::prelude::*;
-use crate::usb::{KeyUsageAndIndex, NKROBootKeyboardReport, KeyboardUsage};
+use crate::clock::Instant;
+use crate::event_queue::EventQueue;
+use crate::usb::{KeyUsageAndIndex, NKROBootKeyboardReport, KeyboardUsage, HostLeds};
 use crate::layout::{Behavior, Keymap, get_layout, behavior_to_utf8};
 
 // Virtual keyboard state follows the QMK model:
@@ -22,11 +24,31 @@ pub struct VKeyboard {
   active_layer_mask: LayerMask,
   key_down_layer: [LayerIndex; MAX_KEYS],
   key_down_mask: KeyMask,
+  // layer of the deep behavior each key is holding, if any
+  key_deep_layer: [Option<LayerIndex>; MAX_KEYS],
   // virtual keymap
   keymap: Keymap,
   // logical state
   usb_report: NKROBootKeyboardReport,
+  // number of held keys sending each usage, so that a usage is only released
+  // when its last source goes up
+  nkro_counts: [u8; NKRO_LEN],
+  modifier_counts: [u8; NUM_MODIFIERS],
+  // modifiers applied on top of held keys, e.g. by Caps Word
+  weak_modifier: u8,
+  // one-shot modifiers currently held, armed for the next key, and held
+  // modifiers that were used by another key (so act as normal modifiers)
+  one_shot_held: u8,
+  one_shot_pending: u8,
+  one_shot_interrupted: u8,
+  // lock state reported by the host
+  host_leds: HostLeds,
+  caps_word: bool,
+  // time of the last key event, for timeouts
+  last_event: Instant,
   pub reset: bool,
+  // request to start auto-calibration
+  pub calibrate: bool,
 }
 
 enum VirtualFunction {
@@ -34,6 +56,10 @@ enum VirtualFunction {
   VBacklightUp,
   VBacklightDown,
   VReset,
+  VCalibrate,
+  VCapsWordToggle,
+  // modifier bit mask
+  VOneShotMod(u8),
   VLayerGoto(LayerIndex),
   VLayerMod(LayerIndex),
   VLayerToggle(LayerIndex),
@@ -48,6 +74,10 @@ enum Action {
   // do nothing
   Nothing,
 }
+fn modifier_mask(usage: KeyboardUsage) -> u8 {
+  1 << (usage as u8 - MIN_MODIFIER)
+}
+
 fn behavior_to_action(behavior: Behavior) -> Action {
   use Behavior::*;
   use KeyboardUsage::*;
@@ -121,7 +151,7 @@ fn behavior_to_action(behavior: Behavior) -> Action {
     Hash => Nothing,
     Tilde => Nothing,
     Dollar => Nothing,
-    /* _ => SendKey(Kui::new(KeyboardCapsLock)), */
+    CapsLock => SendKey(Kui::new(KeyboardCapsLock)),
     F1 => SendKey(Kui::new(KeyboardF1)),
     F2 => SendKey(Kui::new(KeyboardF2)),
     F3 => SendKey(Kui::new(KeyboardF3)),
@@ -166,6 +196,16 @@ fn behavior_to_action(behavior: Behavior) -> Action {
     BacklightUp => Internal(VBacklightUp),
     BacklightDown => Internal(VBacklightDown),
     Reset => Internal(VReset),
+    Calibrate => Internal(VCalibrate),
+    CapsWord => Internal(VCapsWordToggle),
+    OneShotLCtrl => Internal(VOneShotMod(modifier_mask(KeyboardLeftControl))),
+    OneShotLShift => Internal(VOneShotMod(modifier_mask(KeyboardLeftShift))),
+    OneShotLAlt => Internal(VOneShotMod(modifier_mask(KeyboardLeftAlt))),
+    OneShotLGui => Internal(VOneShotMod(modifier_mask(KeyboardLeftGUI))),
+    OneShotRCtrl => Internal(VOneShotMod(modifier_mask(KeyboardRightControl))),
+    OneShotRShift => Internal(VOneShotMod(modifier_mask(KeyboardRightShift))),
+    OneShotRAlt => Internal(VOneShotMod(modifier_mask(KeyboardRightAlt))),
+    OneShotRGui => Internal(VOneShotMod(modifier_mask(KeyboardRightGUI))),
     LayerGoto(i) => Internal(VLayerGoto(i)),
     LayerMod(i) => Internal(VLayerMod(i)),
     LayerToggle(i) => Internal(VLayerToggle(i)),
@@ -194,6 +234,15 @@ fn get_key_down(kd_mask: &KeyMask, idx: usize) -> bool {
   ((kd_mask[kd_idx] >> kd_bit) & 1) == 1
 }
 
+fn get_nkro_key_down(nkro_keys: &[u8], usage: u8) -> bool {
+  if usage < NKRO_MIN_KEY || usage > NKRO_MAX_KEY {
+    return false;
+  }
+  let byte = ((usage - NKRO_MIN_KEY) / 8) as usize;
+  let bit = (usage - NKRO_MIN_KEY) % 8;
+  ((nkro_keys[byte] >> bit) & 1) == 1
+}
+
 impl VKeyboard {
   pub fn new(keymap: Keymap) -> Result<Self, Error> {
     Ok(Self {
@@ -201,48 +250,138 @@ impl VKeyboard {
       active_layer_mask: 0,
       key_down_layer: [0; MAX_KEYS],
       key_down_mask: [0; KEY_MASK_LEN],
+      key_deep_layer: [None; MAX_KEYS],
       keymap,
       usb_report: NKROBootKeyboardReport::default(),
+      nkro_counts: [0; NKRO_LEN],
+      modifier_counts: [0; NUM_MODIFIERS],
+      weak_modifier: 0,
+      one_shot_held: 0,
+      one_shot_pending: 0,
+      one_shot_interrupted: 0,
+      host_leds: HostLeds::default(),
+      caps_word: false,
+      last_event: 0,
       reset: false,
+      calibrate: false,
     })
   }
 
   fn apply_kui_down(&mut self, kui: KeyUsageAndIndex) {
     let report = &mut self.usb_report;
     match kui {
-      KeyUsageAndIndex::Normal { usage, byte, bit } => {
-        for i in 0..report.boot_keys.len() {
-          if report.boot_keys[i] == 0 {
-            report.boot_keys[i] = usage;
-            break;
-          }
-        }
+      KeyUsageAndIndex::Normal { byte, bit, .. } => {
         assert!(bit < 8 && byte < report.nkro_keys.len());
+        let count = &mut self.nkro_counts[8*byte + bit];
+        *count = count.saturating_add(1);
         report.nkro_keys[byte] |= (1 << bit) as u8;
       }
       KeyUsageAndIndex::Modifier { bit } => {
-        report.modifier |= (1 << bit) as u8;
+        let count = &mut self.modifier_counts[bit];
+        *count = count.saturating_add(1);
       }
     }
+    self.sync_modifier();
+    self.sync_boot_keys();
   }
 
   fn apply_kui_up(&mut self, kui: KeyUsageAndIndex) {
     let report = &mut self.usb_report;
     match kui {
-      KeyUsageAndIndex::Normal { usage, byte, bit } => {
-        for i in (0..report.boot_keys.len()).rev() {
-          if report.boot_keys[i] == usage {
-            report.boot_keys[i] = 0;
-            break;
-          }
-        }
+      KeyUsageAndIndex::Normal { byte, bit, .. } => {
         assert!(bit < 8 && byte < report.nkro_keys.len());
-        report.nkro_keys[byte] &= !((1 << bit) as u8);
+        let count = &mut self.nkro_counts[8*byte + bit];
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+          report.nkro_keys[byte] &= !((1 << bit) as u8);
+        }
       }
       KeyUsageAndIndex::Modifier { bit } => {
-        report.modifier &= !((1 << bit) as u8);
+        let count = &mut self.modifier_counts[bit];
+        *count = count.saturating_sub(1);
+      }
+    }
+    self.sync_modifier();
+    self.sync_boot_keys();
+  }
+
+  fn sync_modifier(&mut self) {
+    let mut modifier = self.weak_modifier | self.one_shot_pending;
+    for bit in 0..NUM_MODIFIERS {
+      if self.modifier_counts[bit] > 0 {
+        modifier |= (1 << bit) as u8;
+      }
+    }
+    self.usb_report.modifier = modifier;
+  }
+
+  // Caps Word shifts letters (and turns `-` into `_`) until a key outside the
+  // word is pressed. Letters are left unshifted while the host has Caps Lock
+  // on, since shifting them would undo the lock.
+  fn update_caps_word(&mut self, kui: &KeyUsageAndIndex) {
+    if !self.caps_word {
+      return;
+    }
+    const SHIFT: u8 = 1 << (KeyboardUsage::KeyboardLeftShift as u8 - MIN_MODIFIER);
+    const LETTERS: core::ops::RangeInclusive<u8> =
+      (KeyboardUsage::KeyboardAa as u8)..=(KeyboardUsage::KeyboardZz as u8);
+    const DIGITS: core::ops::RangeInclusive<u8> =
+      (KeyboardUsage::Keyboard1Exclamation as u8)..=(KeyboardUsage::Keyboard0CloseParens as u8);
+    match *kui {
+      KeyUsageAndIndex::Normal { usage, .. } => {
+        if LETTERS.contains(&usage) {
+          if self.host_leds.caps_lock() {
+            self.weak_modifier &= !SHIFT;
+          }
+          else {
+            self.weak_modifier |= SHIFT;
+          }
+        }
+        else if usage == KeyboardUsage::KeyboardDashUnderscore as u8 {
+          self.weak_modifier |= SHIFT;
+        }
+        else if DIGITS.contains(&usage)
+          || usage == KeyboardUsage::KeyboardBackspace as u8
+          || usage == KeyboardUsage::KeyboardDelete as u8 {
+          self.weak_modifier &= !SHIFT;
+        }
+        else {
+          self.caps_word = false;
+          self.weak_modifier &= !SHIFT;
+        }
+      }
+      // modifiers neither continue nor end the word
+      KeyUsageAndIndex::Modifier { .. } => {}
+    }
+  }
+
+  // The 6KRO boot array is derived from the NKRO bitmap, so the two can never
+  // disagree. Keys still held keep their slot and newly held keys are appended
+  // in usage order. With more than 6 keys held, the boot report enters the
+  // ErrorRollOver phantom state (HID 1.11 appendix C) until enough keys are
+  // released. Usages the boot descriptor can't express are left out.
+  fn sync_boot_keys(&mut self) {
+    let report = &mut self.usb_report;
+    let mut boot_keys = [0_u8; 6];
+    let mut n_keys = 0;
+    for &usage in report.boot_keys.iter() {
+      if get_nkro_key_down(&report.nkro_keys, usage) {
+        boot_keys[n_keys] = usage;
+        n_keys += 1;
+      }
+    }
+    for usage in NKRO_MIN_KEY..=BOOT_MAX_KEY {
+      if !get_nkro_key_down(&report.nkro_keys, usage) || boot_keys[..n_keys].contains(&usage) {
+        continue;
       }
+      if n_keys == boot_keys.len() {
+        report.boot_keys = [KeyboardUsage::KeyboardErrorRollOver as u8; 6];
+        return;
+      }
+      boot_keys[n_keys] = usage;
+      n_keys += 1;
     }
+    report.boot_keys = boot_keys;
   }
 
   fn apply_vfunc_down(&mut self, vfunc: VirtualFunction) {
@@ -254,6 +393,23 @@ impl VKeyboard {
       VReset => {
         self.reset = true;
       },
+      VCalibrate => {},
+      VCapsWordToggle => {
+        self.caps_word = !self.caps_word;
+        self.weak_modifier = 0;
+        self.sync_modifier();
+      },
+      VOneShotMod(mask) => {
+        for bit in 0..NUM_MODIFIERS {
+          if (mask >> bit) & 1 == 1 {
+            let count = &mut self.modifier_counts[bit];
+            *count = count.saturating_add(1);
+          }
+        }
+        self.one_shot_held |= mask;
+        self.one_shot_interrupted &= !mask;
+        self.sync_modifier();
+      },
       VLayerGoto(i) => {
         self.active_layer_mask = 1 << (i as LayerMask);
       },
@@ -277,6 +433,26 @@ impl VKeyboard {
       VBacklightUp => {}, // TODO
       VBacklightDown => {}, // TODO
       VReset => {},
+      // on release, so the key is not held while resting values are measured
+      VCalibrate => {
+        self.calibrate = true;
+      },
+      VCapsWordToggle => {},
+      VOneShotMod(mask) => {
+        for bit in 0..NUM_MODIFIERS {
+          if (mask >> bit) & 1 == 1 {
+            let count = &mut self.modifier_counts[bit];
+            *count = count.saturating_sub(1);
+          }
+        }
+        // tapped alone: arm for the next key
+        if self.one_shot_interrupted & mask == 0 {
+          self.one_shot_pending |= mask;
+        }
+        self.one_shot_held &= !mask;
+        self.one_shot_interrupted &= !mask;
+        self.sync_modifier();
+      },
       VLayerGoto(i) => {},
       VLayerMod(i) => {
         self.active_layer_mask &= !(1 << (i as LayerMask));
@@ -289,55 +465,110 @@ impl VKeyboard {
     }
   }
 
-  fn key_down(&mut self, idx: KeyIndex) -> Result<bool, Error> {
-    for i in (0..self.keymap.layers.len()).rev() {
+  // highest active layer with a non-transparent behavior for the key
+  fn resolve(
+    &self, layers: &Vec<Vec<Behavior, MAX_KEYS>, MAX_LAYERS>, idx: KeyIndex)
+    -> Option<(LayerIndex, Behavior)>
+  {
+    for i in (0..layers.len()).rev() {
       if i != self.default_layer as usize && (self.active_layer_mask >> i) & 1 == 0 {
         continue;
       }
-      if idx as usize >= self.keymap.layers[i].len() {
+      if idx as usize >= layers[i].len() {
         continue;
       }
-      let behavior = self.keymap.layers[i][idx as usize];
+      let behavior = layers[i][idx as usize];
       if let Behavior::Transparent = behavior {
         continue;
       }
-      let action = behavior_to_action(behavior);
-      set_key_down(&mut self.key_down_mask, idx as usize, true);
-      self.key_down_layer[idx as usize] = i as LayerIndex;
-      match action {
-        Action::SendKey(kui) => {
-          self.apply_kui_down(kui);
-          return Ok(true);
-        }
-        Action::Internal(vfunc) => {
-          self.apply_vfunc_down(vfunc);
-          return Ok(false);
-        }
-        Action::Nothing => {
-          return Ok(false);
+      return Some((i as LayerIndex, behavior));
+    }
+    None
+  }
+
+  fn press(&mut self, behavior: Behavior) -> bool {
+    match behavior_to_action(behavior) {
+      Action::SendKey(kui) => {
+        self.one_shot_interrupted |= self.one_shot_held;
+        self.update_caps_word(&kui);
+        if let KeyUsageAndIndex::Normal { .. } = kui {
+          // armed one-shot modifiers apply to this key only
+          self.weak_modifier |= self.one_shot_pending;
+          self.one_shot_pending = 0;
         }
+        self.apply_kui_down(kui);
+        true
+      }
+      Action::Internal(vfunc) => {
+        self.apply_vfunc_down(vfunc);
+        false
       }
+      Action::Nothing => false,
     }
-    Ok(false)
   }
 
-  fn key_up(&mut self, idx: KeyIndex) -> Result<bool, Error> {
-    set_key_down(&mut self.key_down_mask, idx as usize, false);
-    let layer = self.key_down_layer[idx as usize];
-    let behavior = self.keymap.layers[layer as usize][idx as usize];
-    let action = behavior_to_action(behavior);
-    match action {
+  fn release(&mut self, behavior: Behavior) -> bool {
+    match behavior_to_action(behavior) {
       Action::SendKey(kui) => {
+        let normal = matches!(kui, KeyUsageAndIndex::Normal { .. });
         self.apply_kui_up(kui);
-        return Ok(true);
+        // weak modifiers apply to the keys pressed under them, and so last
+        // until the last of those is up
+        if normal && self.nkro_counts.iter().all(|&count| count == 0) {
+          self.weak_modifier = 0;
+          self.sync_modifier();
+        }
+        true
       }
       Action::Internal(vfunc) => {
         self.apply_vfunc_up(vfunc);
-        return Ok(false);
+        false
       }
-      Action::Nothing => {
-        return Ok(false);
+      Action::Nothing => false,
+    }
+  }
+
+  fn key_down(&mut self, idx: KeyIndex) -> Result<bool, Error> {
+    let (layer, behavior) = match self.resolve(&self.keymap.layers, idx) {
+      Some(resolved) => resolved,
+      None => return Ok(false),
+    };
+    set_key_down(&mut self.key_down_mask, idx as usize, true);
+    self.key_down_layer[idx as usize] = layer;
+    Ok(self.press(behavior))
+  }
+
+  fn key_up(&mut self, idx: KeyIndex) -> Result<bool, Error> {
+    // already released by a deep press
+    if !get_key_down(&self.key_down_mask, idx as usize) {
+      return Ok(false);
+    }
+    set_key_down(&mut self.key_down_mask, idx as usize, false);
+    let layer = self.key_down_layer[idx as usize];
+    let behavior = self.keymap.layers[layer as usize][idx as usize];
+    Ok(self.release(behavior))
+  }
+
+  // The deep behavior replaces the shallow one, which stays released until
+  // the key next goes down. Keys without a deep behavior are unaffected.
+  fn key_deep_down(&mut self, idx: KeyIndex) -> Result<bool, Error> {
+    let (layer, behavior) = match self.resolve(&self.keymap.deep_layers, idx) {
+      Some(resolved) => resolved,
+      None => return Ok(false),
+    };
+    let released = self.key_up(idx)?;
+    self.key_deep_layer[idx as usize] = Some(layer);
+    let pressed = self.press(behavior);
+    Ok(released || pressed)
+  }
+
+  fn key_deep_up(&mut self, idx: KeyIndex) -> Result<bool, Error> {
+    match self.key_deep_layer[idx as usize].take() {
+      Some(layer) => {
+        let behavior = self.keymap.deep_layers[layer as usize][idx as usize];
+        Ok(self.release(behavior))
       }
+      None => Ok(false),
     }
   }
 
@@ -361,22 +592,10 @@ impl VKeyboard {
             write_fmt(format_args!("\x1b[0m")); // reset style
           }
           else {
-            // TODO: lift common behavior resolution code into separate fn
-            for i in (0..self.keymap.layers.len()).rev() {
-              if i != self.default_layer as usize && (self.active_layer_mask >> i) & 1 == 0 {
-                continue;
-              }
-              if idx as usize >= self.keymap.layers[i].len() {
-                continue;
-              }
-              let behavior = self.keymap.layers[i][idx as usize];
-              if let Behavior::Transparent = behavior {
-                continue;
-              }
+            if let Some((_, behavior)) = self.resolve(&self.keymap.layers, idx as KeyIndex) {
               let key_utf8 = behavior_to_utf8(behavior);
               write_fmt(format_args!("{:^3}", core::str::from_utf8(&key_utf8[..]).unwrap()));
-              break;
-            };
+            }
           }
           None => {
             write_fmt(format_args!("   "));
@@ -393,15 +612,19 @@ impl VKeyboard {
 
   pub fn update(
     &mut self,
-    key_events: Vec<KeyEvent, BUS_WIDTH>,
+    events: &mut EventQueue,
     write_fmt: impl Fn(fmt::Arguments) -> ())
     -> Result<bool, Error>
   {
     let mut updated = false;
-    for event in key_events.into_iter() {
-      let now_updated = match event {
-        KeyEvent::Down(idx) => self.key_down(idx)?,
-        KeyEvent::Up(idx) => self.key_up(idx)?,
+    // events after a reset request are left queued
+    while let Some(event) = events.pop() {
+      self.last_event = event.time;
+      let now_updated = match event.change {
+        KeyChange::Down(idx) => self.key_down(idx)?,
+        KeyChange::Up(idx) => self.key_up(idx)?,
+        KeyChange::DeepDown(idx) => self.key_deep_down(idx)?,
+        KeyChange::DeepUp(idx) => self.key_deep_up(idx)?,
       };
       updated = updated || now_updated;
       if self.reset {
@@ -412,13 +635,284 @@ impl VKeyboard {
     Ok(updated)
   }
 
+  // Drops modes that have timed out since the last key event, called every
+  // tick whether or not keys changed. Returns whether the report changed.
+  pub fn poll(&mut self, now: Instant) -> bool {
+    let timeouts = self.keymap.timeouts;
+    let idle_ms = now.saturating_sub(self.last_event) / 1000;
+    let expired = |timeout_ms: u32| timeout_ms != 0 && idle_ms >= timeout_ms as u64;
+    let mut updated = false;
+    if self.caps_word && expired(timeouts.caps_word_ms) {
+      self.caps_word = false;
+      self.weak_modifier = 0;
+      updated = true;
+    }
+    if self.one_shot_pending != 0 && expired(timeouts.one_shot_ms) {
+      self.one_shot_pending = 0;
+      updated = true;
+    }
+    if updated {
+      self.sync_modifier();
+    }
+    updated
+  }
+
   pub fn get_report<'a>(&'a self) -> &'a NKROBootKeyboardReport {
     &self.usb_report
   }
+
+  pub fn set_host_leds(&mut self, leds: HostLeds) {
+    self.host_leds = leds;
+  }
+
+  pub fn host_leds(&self) -> HostLeds {
+    self.host_leds
+  }
+
+  pub fn caps_word(&self) -> bool {
+    self.caps_word
+  }
+
+  pub fn one_shot_pending(&self) -> bool {
+    self.one_shot_pending != 0
+  }
+
+  pub fn default_layer(&self) -> LayerIndex {
+    self.default_layer
+  }
+
+  pub fn check_default_layer(&self, layer: LayerIndex) -> Result<(), Error> {
+    if layer as usize >= self.keymap.layers.len() {
+      return Err(Error::InvalidSettings);
+    }
+    Ok(())
+  }
+
+  pub fn set_default_layer(&mut self, layer: LayerIndex) -> Result<(), Error> {
+    self.check_default_layer(layer)?;
+    self.default_layer = layer;
+    Ok(())
+  }
+
+  // highest active layer, i.e. the one that keys resolve to first
+  pub fn active_layer(&self) -> LayerIndex {
+    for i in (0..self.keymap.layers.len()).rev() {
+      if i == self.default_layer as usize || (self.active_layer_mask >> i) & 1 == 1 {
+        return i as LayerIndex;
+      }
+    }
+    self.default_layer
+  }
+}
+
+#[derive(Debug,Copy,Clone)]
+pub struct KeyEvent {
+  pub change: KeyChange,
+  pub time: Instant,
 }
 
 #[derive(Debug,Copy,Clone)]
-pub enum KeyEvent {
+pub enum KeyChange {
   Down(KeyIndex),
   Up(KeyIndex),
+  // past the deep trigger, always between Down and Up
+  DeepDown(KeyIndex),
+  DeepUp(KeyIndex),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::layout::{LayoutKind, Timeouts};
+  use crate::clock::{Clock, MockClock};
+
+  fn make_vkbd(layer: &[Behavior]) -> VKeyboard {
+    let mut layers = Vec::new();
+    layers.push(Vec::from_slice(layer).unwrap()).unwrap();
+    VKeyboard::new(Keymap {
+      layout: LayoutKind::LayoutSplit3x6_2, layers,
+      deep_layers: Vec::new(), actuation: Vec::new(), status: None,
+      timeouts: Timeouts::default(),
+    }).unwrap()
+  }
+
+  fn send(vkbd: &mut VKeyboard, change: KeyChange) {
+    send_at(vkbd, change, 0);
+  }
+
+  fn send_at(vkbd: &mut VKeyboard, change: KeyChange, time: Instant) {
+    let mut events = EventQueue::new();
+    events.push(KeyEvent { change, time }).unwrap();
+    vkbd.update(&mut events, |_| {}).unwrap();
+  }
+
+  #[test]
+  fn boot_keys_roll_over() {
+    use Behavior::*;
+    let mut vkbd = make_vkbd(&[A, B, C, D, E, F, G, LShift, VolMute]);
+    // past the boot descriptor's Logical Maximum, so only sent over NKRO
+    send(&mut vkbd, KeyChange::Down(8));
+    assert_eq!(vkbd.get_report().boot_keys, [0; 6]);
+    assert_ne!(vkbd.get_report().nkro_keys, [0; 16]);
+    for i in 0..6 {
+      send(&mut vkbd, KeyChange::Down(i));
+    }
+    assert_eq!(vkbd.get_report().boot_keys, [0x04, 0x05, 0x06, 0x07, 0x08, 0x09]);
+    send(&mut vkbd, KeyChange::Down(6));
+    send(&mut vkbd, KeyChange::Down(7));
+    assert_eq!(vkbd.get_report().boot_keys, [0x01; 6]);
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    send(&mut vkbd, KeyChange::Up(1));
+    assert_eq!(vkbd.get_report().boot_keys, [0x04, 0x06, 0x07, 0x08, 0x09, 0x0a]);
+    send(&mut vkbd, KeyChange::Up(0));
+    send(&mut vkbd, KeyChange::Down(1));
+    assert_eq!(vkbd.get_report().boot_keys, [0x06, 0x07, 0x08, 0x09, 0x0a, 0x05]);
+  }
+
+  #[test]
+  fn shared_usages_are_counted() {
+    use Behavior::*;
+    let mut vkbd = make_vkbd(&[A, A, LShift, LShift]);
+    send(&mut vkbd, KeyChange::Down(0));
+    send(&mut vkbd, KeyChange::Down(1));
+    send(&mut vkbd, KeyChange::Down(2));
+    send(&mut vkbd, KeyChange::Down(3));
+    send(&mut vkbd, KeyChange::Up(0));
+    send(&mut vkbd, KeyChange::Up(3));
+    assert_eq!(vkbd.get_report().nkro_keys[0], 0x04);
+    assert_eq!(vkbd.get_report().boot_keys, [0x04, 0, 0, 0, 0, 0]);
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    send(&mut vkbd, KeyChange::Up(1));
+    send(&mut vkbd, KeyChange::Up(2));
+    assert_eq!(vkbd.get_report().nkro_keys[0], 0);
+    assert_eq!(vkbd.get_report().boot_keys, [0; 6]);
+    assert_eq!(vkbd.get_report().modifier, 0);
+  }
+
+  #[test]
+  fn caps_word_respects_caps_lock() {
+    use Behavior::*;
+    let mut vkbd = make_vkbd(&[CapsWord, A, Minus, Num1, Space]);
+    send(&mut vkbd, KeyChange::Down(0));
+    send(&mut vkbd, KeyChange::Up(0));
+    assert!(vkbd.caps_word());
+    send(&mut vkbd, KeyChange::Down(1));
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    send(&mut vkbd, KeyChange::Up(1));
+    assert_eq!(vkbd.get_report().modifier, 0);
+    send(&mut vkbd, KeyChange::Down(3));
+    assert_eq!(vkbd.get_report().modifier, 0);
+    send(&mut vkbd, KeyChange::Up(3));
+    vkbd.set_host_leds(HostLeds(0x02));
+    send(&mut vkbd, KeyChange::Down(1));
+    assert_eq!(vkbd.get_report().modifier, 0);
+    send(&mut vkbd, KeyChange::Up(1));
+    send(&mut vkbd, KeyChange::Down(2));
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    send(&mut vkbd, KeyChange::Up(2));
+    send(&mut vkbd, KeyChange::Down(4));
+    assert!(!vkbd.caps_word());
+    assert_eq!(vkbd.get_report().modifier, 0);
+  }
+
+  #[test]
+  fn caps_word_holds_through_overlapping_presses() {
+    use Behavior::*;
+    let mut vkbd = make_vkbd(&[CapsWord, A, B, OneShotLShift]);
+    send(&mut vkbd, KeyChange::Down(0));
+    send(&mut vkbd, KeyChange::Up(0));
+    send(&mut vkbd, KeyChange::Down(1));
+    send(&mut vkbd, KeyChange::Down(2));
+    send(&mut vkbd, KeyChange::Up(1));
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    assert_eq!(vkbd.get_report().boot_keys, [0x05, 0, 0, 0, 0, 0]);
+    send(&mut vkbd, KeyChange::Up(2));
+    assert_eq!(vkbd.get_report().modifier, 0);
+    assert!(vkbd.caps_word());
+    // the same for a one-shot modifier while its key is still held
+    send(&mut vkbd, KeyChange::Down(0));
+    send(&mut vkbd, KeyChange::Up(0));
+    send(&mut vkbd, KeyChange::Down(3));
+    send(&mut vkbd, KeyChange::Up(3));
+    send(&mut vkbd, KeyChange::Down(1));
+    send(&mut vkbd, KeyChange::Down(2));
+    send(&mut vkbd, KeyChange::Up(2));
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    send(&mut vkbd, KeyChange::Up(1));
+    assert_eq!(vkbd.get_report().modifier, 0);
+  }
+
+  #[test]
+  fn one_shot_applies_to_next_key() {
+    use Behavior::*;
+    let mut vkbd = make_vkbd(&[OneShotLShift, A, B]);
+    send(&mut vkbd, KeyChange::Down(0));
+    send(&mut vkbd, KeyChange::Up(0));
+    assert!(vkbd.one_shot_pending());
+    send(&mut vkbd, KeyChange::Down(1));
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    assert!(!vkbd.one_shot_pending());
+    send(&mut vkbd, KeyChange::Up(1));
+    send(&mut vkbd, KeyChange::Down(2));
+    assert_eq!(vkbd.get_report().modifier, 0);
+    send(&mut vkbd, KeyChange::Up(2));
+    // held while another key is pressed, acts as a normal modifier
+    send(&mut vkbd, KeyChange::Down(0));
+    send(&mut vkbd, KeyChange::Down(1));
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    send(&mut vkbd, KeyChange::Up(1));
+    send(&mut vkbd, KeyChange::Up(0));
+    assert!(!vkbd.one_shot_pending());
+    assert_eq!(vkbd.get_report().modifier, 0);
+  }
+
+  #[test]
+  fn modes_time_out_when_idle() {
+    use Behavior::*;
+    let mut vkbd = make_vkbd(&[CapsWord, OneShotLShift, A]);
+    vkbd.keymap.timeouts = Timeouts { caps_word_ms: 1000, one_shot_ms: 500 };
+    let clock = MockClock::new(1_000_000);
+    send_at(&mut vkbd, KeyChange::Down(0), clock.now());
+    send_at(&mut vkbd, KeyChange::Up(0), clock.now());
+    send_at(&mut vkbd, KeyChange::Down(1), clock.now());
+    send_at(&mut vkbd, KeyChange::Up(1), clock.now());
+    clock.advance_ms(499);
+    assert!(!vkbd.poll(clock.now()));
+    assert_eq!(vkbd.get_report().modifier, 0x02);
+    clock.advance_ms(1);
+    assert!(vkbd.poll(clock.now()));
+    assert!(!vkbd.one_shot_pending());
+    assert!(vkbd.caps_word());
+    // a key event restarts the idle time
+    send_at(&mut vkbd, KeyChange::Down(2), clock.now());
+    send_at(&mut vkbd, KeyChange::Up(2), clock.now());
+    clock.advance_ms(999);
+    assert!(!vkbd.poll(clock.now()));
+    clock.advance_ms(1);
+    assert!(vkbd.poll(clock.now()));
+    assert!(!vkbd.caps_word());
+    send_at(&mut vkbd, KeyChange::Down(2), clock.now());
+    assert_eq!(vkbd.get_report().modifier, 0);
+  }
+
+  #[test]
+  fn deep_press_replaces_shallow() {
+    use Behavior::*;
+    let mut vkbd = make_vkbd(&[E, ArrowDown]);
+    vkbd.keymap.deep_layers.push(Vec::from_slice(&[Transparent, PageDown]).unwrap()).unwrap();
+    send(&mut vkbd, KeyChange::Down(1));
+    assert_eq!(vkbd.get_report().boot_keys[0], 0x51);
+    send(&mut vkbd, KeyChange::DeepDown(1));
+    assert_eq!(vkbd.get_report().boot_keys, [0x4e, 0, 0, 0, 0, 0]);
+    send(&mut vkbd, KeyChange::DeepUp(1));
+    send(&mut vkbd, KeyChange::Up(1));
+    assert_eq!(vkbd.get_report().boot_keys, [0; 6]);
+    // no deep behavior, the shallow one is kept
+    send(&mut vkbd, KeyChange::Down(0));
+    send(&mut vkbd, KeyChange::DeepDown(0));
+    assert_eq!(vkbd.get_report().boot_keys[0], 0x08);
+    send(&mut vkbd, KeyChange::DeepUp(0));
+    send(&mut vkbd, KeyChange::Up(0));
+    assert_eq!(vkbd.get_report().boot_keys, [0; 6]);
+  }
 }